@@ -0,0 +1,322 @@
+use crate::{debug, Completion, Delivery, Error};
+use rusqlite::{Connection, Statement};
+use std::time::{Duration, Instant};
+
+// The configuration of transaction batching.
+// A transaction is committed after "size" messages or once it has been open for "interval", whichever comes first.
+pub struct BatchConfig {
+    size: usize,
+    interval: Duration,
+}
+
+impl BatchConfig {
+    pub const DEFAULT_SIZE: usize = 100;
+    pub const DEFAULT_INTERVAL_MS: u64 = 1000;
+
+    pub fn new(size: usize, interval: Duration) -> Result<BatchConfig, Error> {
+        if size == 0 {
+            return Err(Error::Config(String::from(
+                "the batch size must be at least 1",
+            )));
+        }
+
+        Ok(BatchConfig { size, interval })
+    }
+}
+
+// Groups the insertions of multiple messages into a single transaction.
+// Every message gets its own savepoint, so a failing message doesn't leave half of its rows behind.
+// Instead, the raw line of a failing message is stored in the "rejected" table, so it can be re-ingested later.
+// The completions of all messages are only invoked once their transaction has been committed (or has failed to).
+pub struct Batch<'c> {
+    db_connection: &'c Connection,
+    insert_rejected: Statement<'c>,
+    config: BatchConfig,
+    deadline: Option<Instant>,
+    completions: Vec<(Option<Completion>, Result<(), Error>)>,
+}
+
+impl<'c> Batch<'c> {
+    pub fn new(db_connection: &'c Connection, config: BatchConfig) -> Result<Batch<'c>, Error> {
+        let insert_rejected = db_connection.prepare(
+            "INSERT INTO rejected
+            	(time, error_kind, error_message, line, raw_line)
+            	VALUES (strftime('%Y-%m-%dT%H:%M:%fZ', 'now'), ?, ?, ?, ?)",
+        )?;
+
+        Ok(Batch {
+            db_connection,
+            insert_rejected,
+            config,
+            deadline: None,
+            completions: Vec::new(),
+        })
+    }
+
+    // The point in time when the open transaction has to be committed (if there is one):
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    // Runs the given insertion for a single message line as part of the current batch.
+    // The returned error is fatal (i.e. the DB is not usable anymore), the one of the insertion is not.
+    pub fn process<F>(
+        &mut self,
+        line: &str,
+        insert: F,
+        completion: Option<Completion>,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce() -> Result<(), Error>,
+    {
+        self.run(line, None, insert, completion)
+    }
+
+    // Stores a message that can't be processed at all (e.g. because it isn't valid UTF-8) as part of the current batch.
+    // Besides its (lossy) line, its exact bytes are kept.
+    pub fn reject(
+        &mut self,
+        bytes: &[u8],
+        err: Error,
+        completion: Option<Completion>,
+    ) -> Result<(), Error> {
+        let line = String::from_utf8_lossy(bytes);
+        self.run(&line, Some(bytes), || Err(err), completion)
+    }
+
+    fn run<F>(
+        &mut self,
+        line: &str,
+        raw_line: Option<&[u8]>,
+        insert: F,
+        completion: Option<Completion>,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce() -> Result<(), Error>,
+    {
+        if self.deadline.is_none() {
+            self.db_connection.execute_batch("BEGIN")?;
+            self.deadline = Some(Instant::now() + self.config.interval);
+        }
+
+        self.db_connection.execute_batch("SAVEPOINT message")?;
+        let result = insert();
+
+        if let Err(err) = &result {
+            self.db_connection.execute_batch("ROLLBACK TO message")?;
+            self.insert_rejected
+                .execute((err.kind(), err.message(), line, raw_line))?;
+        }
+
+        self.db_connection.execute_batch("RELEASE message")?;
+        self.completions.push((completion, result));
+
+        // Under steady input, the loop never waits long enough to notice the deadline itself:
+        let expired = self
+            .deadline
+            .is_some_and(|deadline| deadline <= Instant::now());
+
+        if expired || self.completions.len() >= self.config.size {
+            self.commit()?;
+        }
+
+        Ok(())
+    }
+
+    // Commits the open transaction (if there is one) and completes all messages in it.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if self.deadline.take().is_none() {
+            return Ok(());
+        }
+
+        let result = self.db_connection.execute_batch("COMMIT");
+
+        if result.is_err() {
+            // Nothing of this batch has been stored, so we must not leave the transaction open:
+            _ = self.db_connection.execute_batch("ROLLBACK");
+        }
+
+        let result = result.map_err(Error::from);
+
+        if result.is_ok() {
+            debug!("Committed a batch of {:} messages", self.completions.len());
+        }
+
+        for (completion, msg_result) in self.completions.drain(..) {
+            if let Some(completion) = completion {
+                completion(match (&result, &msg_result) {
+                    (Err(err), _) => Delivery::Failed(err),
+                    (Ok(()), Err(err)) => Delivery::Rejected(err),
+                    (Ok(()), Ok(())) => Delivery::Stored,
+                });
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema;
+    use std::sync::mpsc::{channel, Sender};
+
+    // Reports the delivery of a message through the channel:
+    fn completion(sender: &Sender<(usize, &'static str)>, msg: usize) -> Option<Completion> {
+        let sender = sender.clone();
+
+        Some(Box::new(move |delivery| {
+            let delivery = match delivery {
+                Delivery::Stored => "stored",
+                Delivery::Rejected(_) => "rejected",
+                Delivery::Failed(_) => "failed",
+            };
+            sender.send((msg, delivery)).unwrap();
+        }))
+    }
+
+    fn insert(db_connection: &Connection, msg: usize) -> Result<(), Error> {
+        db_connection.execute(
+            "INSERT INTO applications (app_id) VALUES (?)",
+            [msg.to_string()],
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn commits_after_size() {
+        let db_connection = Connection::open_in_memory().unwrap();
+        schema::migrate(&db_connection).unwrap();
+
+        let (sender, receiver) = channel();
+        let config = BatchConfig::new(2, Duration::from_secs(3600)).unwrap();
+        let mut batch = Batch::new(&db_connection, config).unwrap();
+
+        batch
+            .process("1", || insert(&db_connection, 1), completion(&sender, 1))
+            .unwrap();
+        assert!(batch.deadline().is_some());
+        assert!(receiver.try_recv().is_err());
+
+        // The second message fails, but still counts:
+        batch
+            .process(
+                "2",
+                || Err(Error::Decode(String::from("broken"))),
+                completion(&sender, 2),
+            )
+            .unwrap();
+        assert!(batch.deadline().is_none());
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            [(1, "stored"), (2, "rejected")]
+        );
+
+        let (applications, rejected): (i64, String) = db_connection
+            .query_row(
+                "SELECT (SELECT COUNT(*) FROM applications), (SELECT line FROM rejected)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((applications, rejected.as_str()), (1, "2"));
+    }
+
+    #[test]
+    fn keeps_bytes_of_invalid_lines() {
+        let db_connection = Connection::open_in_memory().unwrap();
+        schema::migrate(&db_connection).unwrap();
+
+        let (sender, receiver) = channel();
+        let config = BatchConfig::new(1, Duration::from_secs(3600)).unwrap();
+        let mut batch = Batch::new(&db_connection, config).unwrap();
+
+        let bytes = b"{\"payload\": \"\xff\"}";
+        let err = String::from_utf8(bytes.to_vec()).unwrap_err();
+        batch
+            .reject(bytes, Error::from(err.utf8_error()), completion(&sender, 1))
+            .unwrap();
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [(1, "rejected")]);
+
+        let rejected: (String, String, Vec<u8>) = db_connection
+            .query_row(
+                "SELECT error_kind, line, raw_line FROM rejected",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            rejected,
+            (
+                String::from("Io"),
+                String::from("{\"payload\": \"\u{fffd}\"}"),
+                bytes.to_vec()
+            )
+        );
+    }
+
+    #[test]
+    fn commits_after_deadline() {
+        let db_connection = Connection::open_in_memory().unwrap();
+        schema::migrate(&db_connection).unwrap();
+
+        let (sender, receiver) = channel();
+        let config = BatchConfig::new(100, Duration::from_millis(20)).unwrap();
+        let mut batch = Batch::new(&db_connection, config).unwrap();
+
+        batch
+            .process("1", || insert(&db_connection, 1), completion(&sender, 1))
+            .unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        // The next message after the deadline commits the batch (without waiting for a pause in the input):
+        std::thread::sleep(Duration::from_millis(30));
+        batch
+            .process("2", || insert(&db_connection, 2), completion(&sender, 2))
+            .unwrap();
+        assert!(batch.deadline().is_none());
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            [(1, "stored"), (2, "stored")]
+        );
+
+        // Committing without an open transaction does nothing:
+        batch.commit().unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn fails_completions_of_a_failed_commit() {
+        let db_connection = Connection::open_in_memory().unwrap();
+        schema::migrate(&db_connection).unwrap();
+        db_connection
+            .execute_batch("PRAGMA foreign_keys = ON")
+            .unwrap();
+
+        let (sender, receiver) = channel();
+        let config = BatchConfig::new(100, Duration::from_secs(3600)).unwrap();
+        let mut batch = Batch::new(&db_connection, config).unwrap();
+
+        batch
+            .process("1", || insert(&db_connection, 1), completion(&sender, 1))
+            .unwrap();
+
+        // A deferred foreign key violation lets the commit fail:
+        db_connection
+            .execute_batch(
+                "PRAGMA defer_foreign_keys = ON;
+                INSERT INTO devices (application_id, dev_id, hardware_serial) VALUES (42, 'node', '');",
+            )
+            .unwrap();
+
+        assert!(batch.commit().is_err());
+        assert_eq!(receiver.try_iter().collect::<Vec<_>>(), [(1, "failed")]);
+
+        // Nothing of the batch has been stored:
+        let applications: i64 = db_connection
+            .query_row("SELECT COUNT(*) FROM applications", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(applications, 0);
+    }
+}
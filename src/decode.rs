@@ -0,0 +1,216 @@
+use crate::{env_var, layout, lpp, plugin::Plugins, script::Script, Error, Uplink};
+use layout::Layout;
+use serde_json::{Map as JSONMap, Value as JSONValue};
+
+// A single value decoded from an uplink payload.
+// It is stored in the "measurements" table.
+pub struct Measurement {
+    pub channel: Option<u32>,
+    pub kind: String,
+    pub value: f64,
+    pub unit: Option<String>,
+}
+
+// What the decoders have made of a payload:
+#[derive(Default)]
+pub struct Decoded {
+    pub measurements: Vec<Measurement>,
+    // Everything a plugin or the script has returned (including what isn't a measurement):
+    pub fields: Option<JSONValue>,
+}
+
+impl Decoded {
+    // The fields of plugins and scripts are numbers, booleans or objects with a numeric "value" and a "unit".
+    // Only those become measurements, everything else (e.g. strings or nested objects) is only kept in "fields".
+    fn from_fields(fields: JSONMap<String, JSONValue>) -> Decoded {
+        let measurements = fields
+            .iter()
+            .filter_map(|(name, field)| {
+                let (value, unit) = match field {
+                    JSONValue::Object(field) => (
+                        field.get("value")?,
+                        field
+                            .get("unit")
+                            .and_then(JSONValue::as_str)
+                            .map(String::from),
+                    ),
+                    field => (field, None),
+                };
+
+                let value = match value {
+                    JSONValue::Bool(value) => f64::from(u8::from(*value)),
+                    JSONValue::Number(value) => value.as_f64()?,
+                    _ => return None,
+                };
+
+                Some(Measurement {
+                    channel: None,
+                    kind: name.clone(),
+                    value,
+                    unit,
+                })
+            })
+            .collect();
+
+        Decoded {
+            measurements,
+            fields: Some(JSONValue::Object(fields)),
+        }
+    }
+
+    fn from_measurements(measurements: Vec<Measurement>) -> Decoded {
+        Decoded {
+            measurements,
+            fields: None,
+        }
+    }
+}
+
+// The ports of the uplinks a decoder is applied to.
+// Port 0 is reserved for MAC commands, so it never carries application data.
+pub enum Ports {
+    All,
+    Only(Vec<u32>),
+}
+
+impl Ports {
+    // Parses "all" or a comma-separated list of ports:
+    fn parse(name: &str, value: &str) -> Result<Ports, Error> {
+        if value == "all" {
+            return Ok(Ports::All);
+        }
+
+        value
+            .split(',')
+            .map(|port| {
+                port.trim()
+                    .parse()
+                    .map_err(|_| Error::Config(format!("invalid port \"{:}\" in {:}", port, name)))
+            })
+            .collect::<Result<_, _>>()
+            .map(Ports::Only)
+    }
+
+    fn contains(&self, port: u32) -> bool {
+        match self {
+            Ports::All => port != 0,
+            Ports::Only(ports) => ports.contains(&port),
+        }
+    }
+}
+
+// Where the payload decoders come from (all of them are optional):
+// "layout_file" describes the payloads of custom sensors, "plugin_file" routes them to WebAssembly plugins.
+// "script_file" is a Rhai script for irregular payloads, "lpp_ports" enables Cayenne LPP for the given ports.
+#[derive(Default)]
+pub struct DecodersConfig {
+    pub layout_file: Option<String>,
+    pub plugin_file: Option<String>,
+    pub script_file: Option<String>,
+    pub lpp_ports: Option<Ports>,
+}
+
+impl DecodersConfig {
+    // Reads "TTN2SQLITE_LAYOUT_FILE", "TTN2SQLITE_PLUGIN_FILE", "TTN2SQLITE_SCRIPT_FILE" and "TTN2SQLITE_LPP_PORTS":
+    pub fn from_env() -> Result<DecodersConfig, Error> {
+        Ok(DecodersConfig {
+            layout_file: env_var("TTN2SQLITE_LAYOUT_FILE"),
+            plugin_file: env_var("TTN2SQLITE_PLUGIN_FILE"),
+            script_file: env_var("TTN2SQLITE_SCRIPT_FILE"),
+            lpp_ports: env_var("TTN2SQLITE_LPP_PORTS")
+                .map(|ports| Ports::parse("TTN2SQLITE_LPP_PORTS", &ports))
+                .transpose()?,
+        })
+    }
+}
+
+// The payload decoders that are applied to every stored uplink.
+// They are tried in this order: A matching layout comes first, then a matching plugin and the script (unless they return nothing), then Cayenne LPP.
+// The default has no decoders at all, so only the raw payloads are stored.
+#[derive(Default)]
+pub struct Decoders {
+    layouts: Vec<Layout>,
+    plugins: Option<Plugins>,
+    script: Option<Script>,
+    lpp_ports: Option<Ports>,
+}
+
+impl Decoders {
+    // Loads (and checks) the files of the configured decoders:
+    pub fn load(config: DecodersConfig) -> Result<Decoders, Error> {
+        let layouts = match &config.layout_file {
+            Some(path) => layout::load(path)?,
+            None => Vec::new(),
+        };
+
+        let plugins = config
+            .plugin_file
+            .as_deref()
+            .map(Plugins::load)
+            .transpose()?;
+
+        let script = config
+            .script_file
+            .as_deref()
+            .map(Script::load)
+            .transpose()?;
+
+        Ok(Decoders {
+            layouts,
+            plugins,
+            script,
+            lpp_ports: config.lpp_ports,
+        })
+    }
+
+    pub fn from_env() -> Result<Decoders, Error> {
+        Decoders::load(DecodersConfig::from_env()?)
+    }
+
+    // Decodes the payload of an uplink into measurements.
+    // A payload that doesn't match its decoder is an error (which is stored along with the uplink).
+    pub fn decode(&self, uplink: &Uplink) -> Result<Decoded, Error> {
+        let payload = uplink.payload.as_slice();
+
+        if let Some(layout) = self.layouts.iter().find(|layout| layout.matches(uplink)) {
+            return layout
+                .decode(payload)
+                .map(Decoded::from_measurements)
+                .map_err(|err| {
+                    Error::Decode(format!("layout of port {:}: {:}", uplink.port, err))
+                });
+        }
+
+        if let Some(plugins) = &self.plugins {
+            let fields = plugins
+                .decode(uplink)
+                .map_err(|err| Error::Decode(format!("plugin: {:}", err)))?;
+
+            if let Some(fields) = fields {
+                return Ok(Decoded::from_fields(fields));
+            }
+        }
+
+        if let Some(script) = &self.script {
+            let fields = script
+                .decode(payload, uplink.port)
+                .map_err(|err| Error::Decode(format!("script: {:}", err)))?;
+
+            if let Some(fields) = fields {
+                return Ok(Decoded::from_fields(fields));
+            }
+        }
+
+        if self
+            .lpp_ports
+            .as_ref()
+            .is_some_and(|ports| ports.contains(uplink.port))
+        {
+            return lpp::decode(payload)
+                .map(Decoded::from_measurements)
+                .map_err(|err| Error::Decode(format!("Cayenne LPP: {:}", err)));
+        }
+
+        Ok(Decoded::default())
+    }
+}
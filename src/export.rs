@@ -0,0 +1,102 @@
+use crate::Error;
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine};
+use rusqlite::{types::ValueRef, Connection};
+use serde_json::{Map, Number, Value as JSONValue};
+use std::io::Write;
+
+// The formats a table can be exported in:
+#[derive(Clone, Copy)]
+pub enum ExportFormat {
+    // One JSON object per row:
+    JsonLines,
+    // A header line with the column names, then one line per row (quoted as in RFC 4180):
+    Csv,
+}
+
+// Converts a single value, blobs (i.e. payloads) become Base64 strings:
+fn json_value(value: ValueRef) -> JSONValue {
+    match value {
+        ValueRef::Null => JSONValue::Null,
+        ValueRef::Integer(value) => JSONValue::from(value),
+        ValueRef::Real(value) => Number::from_f64(value).map_or(JSONValue::Null, JSONValue::Number),
+        ValueRef::Text(value) => JSONValue::from(String::from_utf8_lossy(value)),
+        ValueRef::Blob(value) => JSONValue::from(BASE64.encode(value)),
+    }
+}
+
+fn csv_field(value: ValueRef) -> String {
+    let text = match value {
+        ValueRef::Null => return String::new(),
+        ValueRef::Integer(value) => return value.to_string(),
+        ValueRef::Real(value) => return value.to_string(),
+        ValueRef::Text(value) => String::from_utf8_lossy(value).into_owned(),
+        ValueRef::Blob(value) => return BASE64.encode(value),
+    };
+
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{:}\"", text.replace('"', "\"\""))
+    } else {
+        text
+    }
+}
+
+// Writes all rows of a table (or view, like "uplinks") to "out".
+pub fn run<W: Write>(
+    db_connection: &Connection,
+    table: &str,
+    format: ExportFormat,
+    out: &mut W,
+) -> Result<(), Error> {
+    // Table names can't be bound as parameters, so only the existing ones are accepted:
+    let exists: bool = db_connection.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type IN ('table', 'view') AND name = ?)",
+        [table],
+        |row| row.get(0),
+    )?;
+
+    if !exists {
+        return Err(Error::Config(format!("there is no table \"{:}\"", table)));
+    }
+
+    let mut stmt = db_connection.prepare(&format!(
+        "SELECT * FROM \"{:}\"",
+        table.replace('"', "\"\"")
+    ))?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(String::from).collect();
+
+    if let ExportFormat::Csv = format {
+        let header: Vec<String> = columns
+            .iter()
+            .map(|column| csv_field(ValueRef::Text(column.as_bytes())))
+            .collect();
+
+        writeln!(out, "{:}", header.join(","))?;
+    }
+
+    let mut rows = stmt.query([])?;
+
+    while let Some(row) = rows.next()? {
+        match format {
+            ExportFormat::JsonLines => {
+                let mut object = Map::new();
+
+                for (index, column) in columns.iter().enumerate() {
+                    object.insert(column.clone(), json_value(row.get_ref(index)?));
+                }
+
+                writeln!(out, "{:}", JSONValue::Object(object))?;
+            }
+            ExportFormat::Csv => {
+                let fields = (0..columns.len())
+                    .map(|index| Ok(csv_field(row.get_ref(index)?)))
+                    .collect::<Result<Vec<String>, Error>>()?;
+
+                writeln!(out, "{:}", fields.join(","))?;
+            }
+        }
+    }
+
+    out.flush()?;
+
+    Ok(())
+}
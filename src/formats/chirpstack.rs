@@ -0,0 +1,287 @@
+use super::{deserialize_payload, Probe, SourceFormat};
+use crate::{Error, Payload, RadioSettings, Reception, Uplink, UplinkLocation};
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::Value as JSONValue;
+use std::borrow::Cow;
+
+// Only ChirpStack events carry a "deviceInfo":
+pub struct ChirpStack;
+
+impl SourceFormat for ChirpStack {
+    fn name(&self) -> &'static str {
+        "chirpstack"
+    }
+
+    fn claims(&self, probe: &Probe) -> bool {
+        probe.has("deviceInfo")
+    }
+
+    fn parse<'l>(&self, line: &'l str) -> Result<Uplink<'l>, Error> {
+        Ok(serde_json::from_str::<ChirpStackUplink>(line)?.into())
+    }
+}
+
+// The uplink event of the ChirpStack (v4) JSON integration.
+// Like in TTN v3, fields that hold their default value are omitted.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChirpStackUplink<'l> {
+    #[serde(borrow)]
+    device_info: ChirpStackDeviceInfo<'l>,
+    time: &'l str,
+    #[serde(default)]
+    f_port: u32,
+    #[serde(default)]
+    f_cnt: u32,
+    #[serde(default = "Payload::empty", deserialize_with = "deserialize_payload")]
+    data: Payload,
+    // Decoded by the codec of the device profile:
+    object: Option<JSONValue>,
+    #[serde(default, borrow)]
+    rx_info: Vec<ChirpStackRxInfo<'l>>,
+    #[serde(default, borrow)]
+    tx_info: ChirpStackTxInfo<'l>,
+}
+
+// Applications and devices are identified by their names (instead of their UUIDs), like in TTN:
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChirpStackDeviceInfo<'l> {
+    application_name: &'l str,
+    device_name: &'l str,
+    dev_eui: &'l str,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChirpStackRxInfo<'l> {
+    gateway_id: &'l str,
+    #[serde(rename = "gwTime")]
+    time: Option<&'l str>,
+    channel: Option<u32>,
+    rf_chain: Option<u32>,
+    rssi: Option<f64>,
+    snr: Option<f64>,
+    location: Option<ChirpStackLocation>,
+}
+
+// Gateways without a configured position report an empty location ("location": {}):
+#[derive(Deserialize)]
+struct ChirpStackLocation {
+    longitude: Option<f64>,
+    latitude: Option<f64>,
+    altitude: Option<f64>,
+}
+
+impl<'l> From<ChirpStackRxInfo<'l>> for Reception<'l> {
+    fn from(rx: ChirpStackRxInfo<'l>) -> Self {
+        // A location without coordinates is no location at all:
+        let location = rx
+            .location
+            .and_then(|loc| Some((loc.longitude?, loc.latitude?, loc.altitude)));
+
+        Reception {
+            gtw_id: rx.gateway_id,
+            time: rx.time.map(Cow::Borrowed),
+            timestamp: None,
+            channel: rx.channel,
+            rf_chain: rx.rf_chain,
+            rssi: rx.rssi,
+            snr: rx.snr,
+            longitude: location.map(|(longitude, _, _)| longitude),
+            latitude: location.map(|(_, latitude, _)| latitude),
+            altitude: location.and_then(|(_, _, altitude)| altitude),
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct ChirpStackTxInfo<'l> {
+    frequency: Option<u64>,
+    #[serde(default, borrow)]
+    modulation: ChirpStackModulation<'l>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChirpStackModulation<'l> {
+    #[serde(borrow)]
+    lora: Option<ChirpStackLoRa<'l>>,
+    fsk: Option<IgnoredAny>,
+    lr_fhss: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChirpStackLoRa<'l> {
+    bandwidth: u32,
+    spreading_factor: u32,
+    code_rate: Option<&'l str>,
+}
+
+impl<'l> From<ChirpStackUplink<'l>> for Uplink<'l> {
+    fn from(msg: ChirpStackUplink<'l>) -> Self {
+        let modulation = &msg.tx_info.modulation;
+        let lora = modulation.lora.as_ref();
+
+        let radio = RadioSettings {
+            frequency: msg.tx_info.frequency,
+            modulation: if lora.is_some() {
+                Some("LORA")
+            } else if modulation.fsk.is_some() {
+                Some("FSK")
+            } else if modulation.lr_fhss.is_some() {
+                Some("LR_FHSS")
+            } else {
+                None
+            },
+            data_rate: lora
+                .map(|lora| format!("SF{:}BW{:}", lora.spreading_factor, lora.bandwidth / 1000)),
+            spreading_factor: lora.map(|lora| lora.spreading_factor),
+            bandwidth: lora.map(|lora| lora.bandwidth),
+            // ChirpStack names coding rates like "CR_4_5":
+            coding_rate: lora
+                .and_then(|lora| lora.code_rate)
+                .map(|code_rate| match code_rate {
+                    "CR_4_5" => "4/5",
+                    "CR_4_6" => "4/6",
+                    "CR_4_7" => "4/7",
+                    "CR_4_8" => "4/8",
+                    code_rate => code_rate,
+                }),
+            airtime: None,
+        };
+
+        let gateways: Vec<Reception> = msg.rx_info.into_iter().map(Reception::from).collect();
+
+        Uplink {
+            app_id: msg.device_info.application_name,
+            dev_id: msg.device_info.device_name,
+            hardware_serial: msg.device_info.dev_eui,
+            port: msg.f_port,
+            counter: msg.f_cnt,
+            time: Cow::Borrowed(msg.time),
+            location: UplinkLocation::from_gateways(&gateways),
+            payload: msg.data,
+            radio,
+            decoded: msg.object,
+            frame: None,
+            dev_addr: None,
+            mic_valid: None,
+            gateways,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An uplink event as published by ChirpStack v4 (shortened):
+    const EVENT: &str = r#"{
+        "deduplicationId": "3ac8cd08-7b51-4b6a-9d1f-2b6b5a3f1e6c",
+        "time": "2023-05-04T10:21:13.192Z",
+        "deviceInfo": {
+            "tenantName": "ChirpStack",
+            "applicationId": "0b7e4ca2-4a1b-4e1c-8a3a-1c2d3e4f5a6b",
+            "applicationName": "weather",
+            "deviceName": "station-1",
+            "devEui": "0004a30b001c0530"
+        },
+        "devAddr": "260b1234",
+        "fCnt": 42,
+        "fPort": 2,
+        "data": "AQI=",
+        "object": {"temperature": 21.5},
+        "rxInfo": [
+            {
+                "gatewayId": "a840411e6cf84150",
+                "rssi": -97,
+                "snr": 7.5,
+                "channel": 3,
+                "rfChain": 1,
+                "location": {"latitude": 52.52, "longitude": 13.40, "altitude": 34.0}
+            },
+            {
+                "gatewayId": "a840411e6cf84151",
+                "gwTime": "2023-05-04T10:21:13.180Z",
+                "rssi": -80,
+                "snr": 9.0,
+                "location": {}
+            }
+        ],
+        "txInfo": {
+            "frequency": 868100000,
+            "modulation": {"lora": {"bandwidth": 125000, "spreadingFactor": 7, "codeRate": "CR_4_5"}}
+        }
+    }"#;
+
+    #[test]
+    fn parses_uplink_event() {
+        let uplink = ChirpStack.parse(EVENT).unwrap();
+
+        assert_eq!(uplink.app_id, "weather");
+        assert_eq!(uplink.dev_id, "station-1");
+        assert_eq!(uplink.hardware_serial, "0004a30b001c0530");
+        assert_eq!((uplink.port, uplink.counter), (2, 42));
+        assert_eq!(uplink.time, "2023-05-04T10:21:13.192Z");
+        assert_eq!(uplink.payload.as_slice(), [1, 2]);
+        assert_eq!(uplink.decoded.unwrap()["temperature"], 21.5);
+
+        assert_eq!(uplink.radio.frequency, Some(868_100_000));
+        assert_eq!(uplink.radio.modulation, Some("LORA"));
+        assert_eq!(uplink.radio.data_rate.as_deref(), Some("SF7BW125"));
+        assert_eq!(uplink.radio.spreading_factor, Some(7));
+        assert_eq!(uplink.radio.bandwidth, Some(125_000));
+        assert_eq!(uplink.radio.coding_rate, Some("4/5"));
+
+        assert_eq!(uplink.gateways.len(), 2);
+        assert_eq!(uplink.gateways[0].gtw_id, "a840411e6cf84150");
+        assert_eq!(uplink.gateways[0].channel, Some(3));
+        assert_eq!(uplink.gateways[0].rf_chain, Some(1));
+        assert_eq!(uplink.gateways[0].longitude, Some(13.40));
+        assert_eq!(
+            uplink.gateways[1].time.as_deref(),
+            Some("2023-05-04T10:21:13.180Z")
+        );
+    }
+
+    #[test]
+    fn ignores_locations_without_coordinates() {
+        let uplink = ChirpStack.parse(EVENT).unwrap();
+
+        // The second gateway has the better RSSI, but no location (instead of one at 0, 0):
+        let gateway = &uplink.gateways[1];
+        assert_eq!(
+            (gateway.longitude, gateway.latitude, gateway.altitude),
+            (None, None, None)
+        );
+
+        let location = uplink.location.unwrap();
+        assert_eq!(
+            (location.longitude, location.latitude, location.altitude),
+            (13.40, 52.52, Some(34.0))
+        );
+        assert_eq!(location.source.as_str(), "gateway");
+    }
+
+    #[test]
+    fn defaults_omitted_fields() {
+        let uplink = ChirpStack
+            .parse(
+                r#"{
+                    "time": "2023-05-04T10:21:13.192Z",
+                    "deviceInfo": {"applicationName": "weather", "deviceName": "station-1", "devEui": "0004a30b001c0530"},
+                    "txInfo": {"frequency": 868100000, "modulation": {"fsk": {"datarate": 50000}}}
+                }"#,
+            )
+            .unwrap();
+
+        assert_eq!((uplink.port, uplink.counter), (0, 0));
+        assert!(uplink.payload.as_slice().is_empty());
+        assert!(uplink.decoded.is_none());
+        assert!(uplink.location.is_none());
+        assert_eq!(uplink.radio.modulation, Some("FSK"));
+        assert!(uplink.radio.coding_rate.is_none());
+    }
+}
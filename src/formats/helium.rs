@@ -0,0 +1,262 @@
+use super::{deserialize_payload, Probe, SourceFormat};
+use crate::{Error, Payload, RadioSettings, Reception, Uplink, UplinkLocation};
+use chrono::{DateTime, SecondsFormat};
+use serde::Deserialize;
+use serde_json::Value as JSONValue;
+use std::{
+    borrow::Cow,
+    convert::{TryFrom, TryInto},
+};
+
+// Only Helium uplinks carry "hotspots":
+pub struct Helium;
+
+impl SourceFormat for Helium {
+    fn name(&self) -> &'static str {
+        "helium"
+    }
+
+    fn claims(&self, probe: &Probe) -> bool {
+        probe.has("hotspots")
+    }
+
+    fn parse<'l>(&self, line: &'l str) -> Result<Uplink<'l>, Error> {
+        serde_json::from_str::<HeliumUplink>(line)?.try_into()
+    }
+}
+
+// The uplink of the Helium console HTTP integration.
+// Devices are identified by their name, applications by their AppEUI.
+#[derive(Deserialize)]
+struct HeliumUplink<'l> {
+    app_eui: &'l str,
+    dev_eui: &'l str,
+    name: &'l str,
+    fcnt: u32,
+    port: u32,
+    // Milliseconds since the Unix epoch:
+    reported_at: i64,
+    #[serde(deserialize_with = "deserialize_payload")]
+    payload: Payload,
+    #[serde(default, borrow)]
+    hotspots: Vec<HeliumHotspot<'l>>,
+    decoded: Option<HeliumDecoded>,
+}
+
+#[derive(Deserialize)]
+struct HeliumHotspot<'l> {
+    name: &'l str,
+    reported_at: Option<i64>,
+    channel: Option<u32>,
+    // The frequency is given in MHz:
+    frequency: Option<f64>,
+    spreading: Option<&'l str>,
+    rssi: Option<f64>,
+    snr: Option<f64>,
+    lat: Option<f64>,
+    long: Option<f64>,
+}
+
+// The result of the decoder function of the console:
+#[derive(Deserialize)]
+struct HeliumDecoded {
+    payload: Option<JSONValue>,
+    status: Option<String>,
+}
+
+// Formats milliseconds since the Unix epoch like the times of the other formats:
+fn format_millis(millis: i64) -> Option<String> {
+    DateTime::from_timestamp_millis(millis)
+        .map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true))
+}
+
+impl<'l> From<HeliumHotspot<'l>> for Reception<'l> {
+    fn from(hotspot: HeliumHotspot<'l>) -> Self {
+        Reception {
+            gtw_id: hotspot.name,
+            time: hotspot.reported_at.and_then(format_millis).map(Cow::Owned),
+            timestamp: None,
+            channel: hotspot.channel,
+            rf_chain: None,
+            rssi: hotspot.rssi,
+            snr: hotspot.snr,
+            longitude: hotspot.long,
+            latitude: hotspot.lat,
+            altitude: None,
+        }
+    }
+}
+
+impl<'l> TryFrom<HeliumUplink<'l>> for Uplink<'l> {
+    type Error = Error;
+
+    fn try_from(msg: HeliumUplink<'l>) -> Result<Self, Error> {
+        let time = format_millis(msg.reported_at)
+            .ok_or_else(|| Error::Time(format!("invalid time {:} ms", msg.reported_at)))?;
+
+        // Every hotspot reports the radio settings, they are the same for all of them:
+        let first = msg.hotspots.first();
+        let data_rate = first.and_then(|hotspot| hotspot.spreading);
+        let lora = data_rate.and_then(RadioSettings::parse_lora_data_rate);
+
+        let radio = RadioSettings {
+            frequency: first
+                .and_then(|hotspot| hotspot.frequency)
+                .map(|mhz| (mhz * 1e6).round() as u64),
+            modulation: lora.map(|_| "LORA"),
+            data_rate: data_rate.map(String::from),
+            spreading_factor: lora.map(|(sf, _)| sf),
+            bandwidth: lora.map(|(_, bw)| bw),
+            coding_rate: None,
+            airtime: None,
+        };
+
+        let gateways: Vec<Reception> = msg.hotspots.into_iter().map(Reception::from).collect();
+
+        Ok(Uplink {
+            app_id: msg.app_eui,
+            dev_id: msg.name,
+            hardware_serial: msg.dev_eui,
+            port: msg.port,
+            counter: msg.fcnt,
+            time: Cow::Owned(time),
+            location: UplinkLocation::from_gateways(&gateways),
+            payload: msg.payload,
+            radio,
+            decoded: msg
+                .decoded
+                .filter(|decoded| decoded.status.as_deref() == Some("success"))
+                .and_then(|decoded| decoded.payload),
+            frame: None,
+            dev_addr: None,
+            mic_valid: None,
+            gateways,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An uplink of the Helium console HTTP integration (shortened):
+    const UPLINK: &str = r#"{
+        "app_eui": "70B3D57ED0000000",
+        "dev_eui": "0004A30B001C0530",
+        "devaddr": "3400000A",
+        "id": "a8e5d2a4-8f6e-4c1a-9f3b-0c6b2d1e4f5a",
+        "name": "station-1",
+        "fcnt": 42,
+        "port": 2,
+        "reported_at": 1683195673192,
+        "payload": "AQI=",
+        "payload_size": 2,
+        "type": "uplink",
+        "hotspots": [
+            {
+                "id": "11a2b3c4d5e6f7",
+                "name": "wobbly-purple-fox",
+                "reported_at": 1683195673180,
+                "channel": 5,
+                "frequency": 868.1,
+                "spreading": "SF7BW125",
+                "rssi": -97,
+                "snr": 7.5,
+                "lat": 52.52,
+                "long": 13.40,
+                "status": "success"
+            },
+            {
+                "id": "22b3c4d5e6f7a8",
+                "name": "shiny-green-owl",
+                "reported_at": 1683195673185,
+                "channel": 2,
+                "frequency": 867.5,
+                "spreading": "SF9BW125",
+                "rssi": -80,
+                "snr": 9.0,
+                "status": "success"
+            }
+        ],
+        "decoded": {"payload": {"temperature": 21.5}, "status": "success"}
+    }"#;
+
+    #[test]
+    fn parses_uplink() {
+        let uplink = Helium.parse(UPLINK).unwrap();
+
+        assert_eq!(uplink.app_id, "70B3D57ED0000000");
+        assert_eq!(uplink.dev_id, "station-1");
+        assert_eq!(uplink.hardware_serial, "0004A30B001C0530");
+        assert_eq!((uplink.port, uplink.counter), (2, 42));
+        assert_eq!(uplink.payload.as_slice(), [1, 2]);
+        assert_eq!(uplink.decoded.unwrap()["temperature"], 21.5);
+
+        // Milliseconds since the epoch become RFC 3339 (like the times of the other formats):
+        assert_eq!(uplink.time, "2023-05-04T10:21:13.192Z");
+        assert_eq!(
+            uplink.gateways[1].time.as_deref(),
+            Some("2023-05-04T10:21:13.185Z")
+        );
+
+        // The radio settings are taken from the first hotspot:
+        assert_eq!(uplink.radio.frequency, Some(868_100_000));
+        assert_eq!(uplink.radio.modulation, Some("LORA"));
+        assert_eq!(uplink.radio.data_rate.as_deref(), Some("SF7BW125"));
+        assert_eq!(uplink.radio.spreading_factor, Some(7));
+        assert_eq!(uplink.radio.bandwidth, Some(125_000));
+
+        assert_eq!(uplink.gateways.len(), 2);
+        assert_eq!(uplink.gateways[0].gtw_id, "wobbly-purple-fox");
+        assert_eq!(uplink.gateways[0].channel, Some(5));
+
+        // The second hotspot has the better RSSI, but no location:
+        let location = uplink.location.unwrap();
+        assert_eq!((location.longitude, location.latitude), (13.40, 52.52));
+    }
+
+    #[test]
+    fn ignores_failed_decoders() {
+        let decoded = |decoded: &str| {
+            let line = UPLINK.replace(
+                r#""decoded": {"payload": {"temperature": 21.5}, "status": "success"}"#,
+                decoded,
+            );
+
+            Helium.parse(&line).unwrap().decoded
+        };
+
+        assert!(
+            decoded(r#""decoded": {"payload": {"temperature": 21.5}, "status": "success"}"#)
+                .is_some()
+        );
+        assert!(decoded(
+            r#""decoded": {"payload": null, "status": "error", "error": "no decoder"}"#
+        )
+        .is_none());
+        assert!(decoded(r#""decoded": {"payload": {"temperature": 21.5}}"#).is_none());
+        assert!(decoded(r#""no_decoder": true"#).is_none());
+    }
+
+    #[test]
+    fn handles_uplinks_without_hotspots() {
+        let line = r#"{
+            "app_eui": "70B3D57ED0000000", "dev_eui": "0004A30B001C0530", "name": "station-1",
+            "fcnt": 0, "port": 1, "reported_at": 0, "payload": ""
+        }"#;
+        let uplink = Helium.parse(line).unwrap();
+
+        assert_eq!(uplink.time, "1970-01-01T00:00:00.000Z");
+        assert!(uplink.gateways.is_empty());
+        assert!(uplink.location.is_none());
+        assert!(uplink.radio.frequency.is_none());
+        assert!(uplink.radio.data_rate.is_none());
+    }
+
+    #[test]
+    fn refuses_times_out_of_range() {
+        let line = UPLINK.replace("1683195673192", "9223372036854775807");
+
+        assert!(Helium.parse(&line).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+mod chirpstack;
+mod helium;
+mod raw_frame;
+mod ttn_v2;
+mod ttn_v3;
+
+use crate::{env_var, lorawan::DataFrame, Error, Payload, Uplink};
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine};
+use serde::{
+    de::{Error as _, IgnoredAny},
+    Deserialize, Deserializer,
+};
+use serde_json::Error as JSONError;
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+// An input format we understand.
+// To support another one, implement this trait and add it to "Formats::default" (nothing else has to be touched).
+// Users of the library may add their own ones by "Formats::add".
+pub trait SourceFormat {
+    // The name that forces this format in "TTN2SQLITE_FORMAT" (e.g. "ttn-v3"):
+    fn name(&self) -> &'static str;
+
+    // Whether a line looks like this format, judged by the names of its top-level fields:
+    fn claims(&self, probe: &Probe) -> bool;
+
+    // Deserializes a line into our normalized record:
+    fn parse<'l>(&self, line: &'l str) -> Result<Uplink<'l>, Error>;
+}
+
+// The top-level field names of a line.
+// They are collected once, so the formats can tell whether they are responsible without deserializing the line for real.
+pub struct Probe {
+    fields: BTreeMap<String, IgnoredAny>,
+}
+
+impl Probe {
+    fn new(line: &str) -> Result<Probe, Error> {
+        Ok(Probe {
+            fields: serde_json::from_str(line)?,
+        })
+    }
+
+    pub fn has(&self, field: &str) -> bool {
+        self.fields.contains_key(field)
+    }
+}
+
+// All formats we understand, in the order they are asked to claim a line.
+// The format of every line is detected on its own (so sources may mix them) unless one is forced by "TTN2SQLITE_FORMAT".
+pub struct Formats {
+    formats: Vec<Box<dyn SourceFormat>>,
+    forced: Option<usize>,
+}
+
+impl Default for Formats {
+    // All built-in formats, detected per line:
+    fn default() -> Formats {
+        Formats {
+            formats: vec![
+                Box::new(ttn_v3::TtnV3),
+                Box::new(chirpstack::ChirpStack),
+                Box::new(helium::Helium),
+                Box::new(raw_frame::RawFrame),
+                Box::new(ttn_v2::TtnV2),
+            ],
+            forced: None,
+        }
+    }
+}
+
+impl Formats {
+    pub fn from_env() -> Result<Formats, Error> {
+        let mut formats = Formats::default();
+
+        if let Some(name) = env_var("TTN2SQLITE_FORMAT") {
+            formats.force(&name)?;
+        }
+
+        Ok(formats)
+    }
+
+    // Skips the detection and parses every line in the format with the given name ("auto" brings the detection back):
+    pub fn force(&mut self, name: &str) -> Result<(), Error> {
+        if name == "auto" {
+            self.forced = None;
+            return Ok(());
+        }
+
+        let index = self
+            .formats
+            .iter()
+            .position(|format| format.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = self.formats.iter().map(|format| format.name()).collect();
+
+                Error::Config(format!(
+                    "unknown format \"{:}\" (valid are auto, {:})",
+                    name,
+                    names.join(", ")
+                ))
+            })?;
+
+        self.forced = Some(index);
+
+        Ok(())
+    }
+
+    // Adds a format that isn't built in.
+    // It is asked before the others, so it may also take over lines they would claim.
+    pub fn add(&mut self, format: Box<dyn SourceFormat>) {
+        self.formats.insert(0, format);
+        self.forced = self.forced.map(|index| index + 1);
+    }
+
+    // Detects the format of a line (unless it is forced) and deserializes it.
+    // The name of the format is returned along with the record.
+    pub fn parse<'l>(&self, line: &'l str) -> Result<(&'static str, Uplink<'l>), Error> {
+        let format = match self.forced {
+            Some(index) => &self.formats[index],
+            None => {
+                let probe = Probe::new(line)?;
+
+                self.formats
+                    .iter()
+                    .find(|format| format.claims(&probe))
+                    .ok_or_else(|| {
+                        Error::Json(JSONError::custom("message doesn't match any known format"))
+                    })?
+            }
+        };
+
+        Ok((format.name(), format.parse(line)?))
+    }
+}
+
+// This function deserializes a Base64-encoded payload (e.g. "payload_raw" of TTN v2, "frm_payload" of TTN v3 or "data" of ChirpStack).
+pub fn deserialize_payload<'de, D>(deserializer: D) -> Result<Payload, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // Extract the JSON value as string slice:
+    let input = <&str as Deserialize>::deserialize(deserializer)?;
+
+    // Decode the Base64 string into our array:
+    let mut payload = Payload::empty();
+    payload.size = BASE64
+        .decode_slice(input, &mut payload.bytes)
+        .map_err(|err| D::Error::custom(err.to_string()))?;
+
+    Ok(payload)
+}
+
+// This function deserializes a Base64-encoded PHYPayload into a data frame.
+fn deserialize_frame<'de, D>(deserializer: D) -> Result<DataFrame, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input = <&str as Deserialize>::deserialize(deserializer)?;
+    let bytes = BASE64
+        .decode(input)
+        .map_err(|err| D::Error::custom(err.to_string()))?;
+
+    DataFrame::parse(bytes).map_err(D::Error::custom)
+}
+
+// This function deserializes a number that is encoded as JSON string (e.g. "868100000").
+fn deserialize_stringified<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let input = <&str as Deserialize>::deserialize(deserializer)?;
+
+    input.parse().map(Some).map_err(D::Error::custom)
+}
+
+// This function deserializes a protobuf duration string (e.g. "0.061696s") into nanoseconds.
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let input = <&str as Deserialize>::deserialize(deserializer)?;
+    let secs = input
+        .strip_suffix('s')
+        .and_then(|secs| secs.parse::<f64>().ok())
+        .ok_or_else(|| D::Error::custom(format!("invalid duration \"{:}\"", input)))?;
+
+    Ok(Some((secs * 1e9).round() as u64))
+}
@@ -0,0 +1,75 @@
+use super::{deserialize_frame, ttn_v2::UplinkGateway, Probe, SourceFormat};
+use crate::{lorawan::DataFrame, Error, Payload, RadioSettings, Reception, Uplink, UplinkLocation};
+use serde::Deserialize;
+use std::borrow::Cow;
+
+// Only raw frames carry a "phy_payload":
+pub struct RawFrame;
+
+impl SourceFormat for RawFrame {
+    fn name(&self) -> &'static str {
+        "raw-frame"
+    }
+
+    fn claims(&self, probe: &Probe) -> bool {
+        probe.has("phy_payload")
+    }
+
+    fn parse<'l>(&self, line: &'l str) -> Result<Uplink<'l>, Error> {
+        Ok(serde_json::from_str::<RawFrameMessage>(line)?.into())
+    }
+}
+
+// Raw LoRaWAN frames, e.g. as received by a packet forwarder:
+#[derive(Deserialize)]
+struct RawFrameMessage<'l> {
+    // The PHYPayload as Base64 string:
+    #[serde(deserialize_with = "deserialize_frame")]
+    phy_payload: DataFrame,
+    time: &'l str,
+
+    // The frequency is given in Hz, the airtime in ns:
+    frequency: Option<u64>,
+    modulation: Option<&'l str>,
+    data_rate: Option<&'l str>,
+    coding_rate: Option<&'l str>,
+    airtime: Option<u64>,
+    #[serde(default, borrow)]
+    gateways: Vec<UplinkGateway<'l>>,
+}
+
+impl<'l> From<RawFrameMessage<'l>> for Uplink<'l> {
+    fn from(msg: RawFrameMessage<'l>) -> Self {
+        let lora = msg.data_rate.and_then(RadioSettings::parse_lora_data_rate);
+
+        let radio = RadioSettings {
+            frequency: msg.frequency,
+            modulation: msg.modulation,
+            data_rate: msg.data_rate.map(String::from),
+            spreading_factor: lora.map(|(sf, _)| sf),
+            bandwidth: lora.map(|(_, bw)| bw),
+            coding_rate: msg.coding_rate,
+            airtime: msg.airtime,
+        };
+
+        let gateways: Vec<Reception> = msg.gateways.into_iter().map(Reception::from).collect();
+
+        // The device is unknown until the frame has been opened with its session keys (or attributed to its DevAddr):
+        Uplink {
+            app_id: "",
+            dev_id: "",
+            hardware_serial: "",
+            port: msg.phy_payload.f_port.map_or(0, u32::from),
+            counter: u32::from(msg.phy_payload.f_cnt),
+            time: Cow::Borrowed(msg.time),
+            location: UplinkLocation::from_gateways(&gateways),
+            payload: Payload::empty(),
+            radio,
+            decoded: None,
+            dev_addr: Some(msg.phy_payload.dev_addr),
+            frame: Some(msg.phy_payload),
+            mic_valid: None,
+            gateways,
+        }
+    }
+}
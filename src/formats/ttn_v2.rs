@@ -0,0 +1,148 @@
+use super::{deserialize_payload, Probe, SourceFormat};
+use crate::{Error, LocationSource, Payload, RadioSettings, Reception, Uplink, UplinkLocation};
+use serde::Deserialize;
+use serde_json::Value as JSONValue;
+use std::borrow::Cow;
+
+// TTN v2 messages carry an "app_id" and a "dev_id" on the top level:
+pub struct TtnV2;
+
+impl SourceFormat for TtnV2 {
+    fn name(&self) -> &'static str {
+        "ttn-v2"
+    }
+
+    fn claims(&self, probe: &Probe) -> bool {
+        probe.has("app_id") && probe.has("dev_id")
+    }
+
+    fn parse<'l>(&self, line: &'l str) -> Result<Uplink<'l>, Error> {
+        Ok(serde_json::from_str::<UplinkMessage>(line)?.into())
+    }
+}
+
+// The data format returned from TTN v2:
+#[derive(Deserialize)]
+struct UplinkMessage<'l> {
+    app_id: &'l str,
+    dev_id: &'l str,
+    hardware_serial: &'l str,
+    port: u32,
+    counter: u32,
+    metadata: UplinkMetadata<'l>,
+
+    // The payload is a blob of up to Payload::MAX_PAYLOAD_SIZE bytes.
+    // It is stored as Base64 string (JSON field name is "payload_raw").
+    // The function "deserialize_payload" (defined in the "formats" module) manages its deserialization.
+    #[serde(rename = "payload_raw", deserialize_with = "deserialize_payload")]
+    payload: Payload,
+    payload_fields: Option<JSONValue>,
+}
+
+#[derive(Deserialize)]
+struct UplinkMetadata<'l> {
+    time: &'l str,
+
+    // The frequency is given in MHz, the airtime in ns:
+    frequency: Option<f64>,
+    modulation: Option<&'l str>,
+    data_rate: Option<&'l str>,
+    coding_rate: Option<&'l str>,
+    airtime: Option<u64>,
+
+    // Devices without a configured location don't report any of these:
+    longitude: Option<f64>,
+    latitude: Option<f64>,
+    altitude: Option<f64>,
+    location_source: Option<&'l str>,
+    #[serde(default, borrow)]
+    gateways: Vec<UplinkGateway<'l>>,
+}
+
+#[derive(Deserialize)]
+pub struct UplinkGateway<'l> {
+    gtw_id: &'l str,
+    timestamp: Option<u32>,
+    time: Option<&'l str>,
+    channel: Option<u32>,
+    rf_chain: Option<u32>,
+    rssi: Option<f64>,
+    snr: Option<f64>,
+    longitude: Option<f64>,
+    latitude: Option<f64>,
+    altitude: Option<f64>,
+}
+
+impl<'l> From<UplinkGateway<'l>> for Reception<'l> {
+    fn from(gtw: UplinkGateway<'l>) -> Self {
+        Reception {
+            gtw_id: gtw.gtw_id,
+            // Gateways without GPS report an empty time string:
+            time: gtw.time.filter(|time| !time.is_empty()).map(Cow::Borrowed),
+            timestamp: gtw.timestamp,
+            channel: gtw.channel,
+            rf_chain: gtw.rf_chain,
+            rssi: gtw.rssi,
+            snr: gtw.snr,
+            longitude: gtw.longitude,
+            latitude: gtw.latitude,
+            altitude: gtw.altitude,
+        }
+    }
+}
+
+impl<'l> From<UplinkMessage<'l>> for Uplink<'l> {
+    fn from(msg: UplinkMessage<'l>) -> Self {
+        let metadata = &msg.metadata;
+        let lora = metadata
+            .data_rate
+            .and_then(RadioSettings::parse_lora_data_rate);
+
+        let radio = RadioSettings {
+            frequency: metadata.frequency.map(|mhz| (mhz * 1e6).round() as u64),
+            modulation: metadata.modulation,
+            data_rate: metadata.data_rate.map(String::from),
+            spreading_factor: lora.map(|(sf, _)| sf),
+            bandwidth: lora.map(|(_, bw)| bw),
+            coding_rate: metadata.coding_rate,
+            airtime: metadata.airtime,
+        };
+
+        let gateways: Vec<Reception> = msg
+            .metadata
+            .gateways
+            .into_iter()
+            .map(Reception::from)
+            .collect();
+
+        let location = match (msg.metadata.longitude, msg.metadata.latitude) {
+            (Some(longitude), Some(latitude)) => Some(UplinkLocation {
+                longitude,
+                latitude,
+                altitude: msg.metadata.altitude,
+                source: msg
+                    .metadata
+                    .location_source
+                    .map_or(LocationSource::Registry, LocationSource::from_v2),
+            }),
+            _ => UplinkLocation::from_gateways(&gateways),
+        };
+
+        Uplink {
+            app_id: msg.app_id,
+            dev_id: msg.dev_id,
+            hardware_serial: msg.hardware_serial,
+            port: msg.port,
+            counter: msg.counter,
+            time: Cow::Borrowed(msg.metadata.time),
+            location,
+            payload: msg.payload,
+            radio,
+            decoded: msg.payload_fields,
+            frame: None,
+            dev_addr: None,
+            mic_valid: None,
+            gateways,
+        }
+    }
+}
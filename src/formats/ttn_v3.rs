@@ -0,0 +1,368 @@
+use super::{
+    deserialize_duration, deserialize_payload, deserialize_stringified, Probe, SourceFormat,
+};
+use crate::{Error, LocationSource, Payload, RadioSettings, Reception, Uplink, UplinkLocation};
+use serde::{de::IgnoredAny, Deserialize};
+use serde_json::Value as JSONValue;
+use std::{borrow::Cow, collections::BTreeMap};
+
+// Only The Things Stack messages carry an "end_device_ids" object:
+pub struct TtnV3;
+
+impl SourceFormat for TtnV3 {
+    fn name(&self) -> &'static str {
+        "ttn-v3"
+    }
+
+    fn claims(&self, probe: &Probe) -> bool {
+        probe.has("end_device_ids")
+    }
+
+    fn parse<'l>(&self, line: &'l str) -> Result<Uplink<'l>, Error> {
+        Ok(serde_json::from_str::<UplinkMessageV3>(line)?.into())
+    }
+}
+
+// The data format returned from The Things Stack (TTN v3).
+// Fields that hold their default value (e.g. a frame counter of 0) are omitted by the stack.
+#[derive(Deserialize)]
+struct UplinkMessageV3<'l> {
+    #[serde(borrow)]
+    end_device_ids: EndDeviceIdentifiers<'l>,
+    #[serde(borrow)]
+    uplink_message: ApplicationUplink<'l>,
+}
+
+#[derive(Deserialize)]
+struct EndDeviceIdentifiers<'l> {
+    device_id: &'l str,
+    #[serde(borrow)]
+    application_ids: ApplicationIdentifiers<'l>,
+
+    // ABP devices don't need a DevEUI, so the stack omits it for those without one:
+    #[serde(default)]
+    dev_eui: &'l str,
+}
+
+#[derive(Deserialize)]
+struct ApplicationIdentifiers<'l> {
+    application_id: &'l str,
+}
+
+#[derive(Deserialize)]
+struct ApplicationUplink<'l> {
+    #[serde(default)]
+    f_port: u32,
+    #[serde(default)]
+    f_cnt: u32,
+    received_at: &'l str,
+    #[serde(default, borrow)]
+    locations: BTreeMap<&'l str, Location<'l>>,
+    #[serde(default, borrow)]
+    rx_metadata: Vec<RxMetadata<'l>>,
+    #[serde(default, borrow)]
+    settings: TxSettings<'l>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    consumed_airtime: Option<u64>,
+
+    // Same as "payload_raw" in TTN v2, but it may be missing for empty frames.
+    #[serde(default = "Payload::empty", deserialize_with = "deserialize_payload")]
+    frm_payload: Payload,
+    // Same as "payload_fields" in TTN v2:
+    decoded_payload: Option<JSONValue>,
+}
+
+#[derive(Default, Deserialize)]
+struct TxSettings<'l> {
+    #[serde(default)]
+    data_rate: DataRate<'l>,
+    // Older stack versions report the coding rate here instead of in the data rate:
+    coding_rate: Option<&'l str>,
+    #[serde(default, deserialize_with = "deserialize_stringified")]
+    frequency: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+struct DataRate<'l> {
+    #[serde(borrow)]
+    lora: Option<LoRaDataRate<'l>>,
+    fsk: Option<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+struct LoRaDataRate<'l> {
+    bandwidth: u32,
+    spreading_factor: u32,
+    coding_rate: Option<&'l str>,
+}
+
+#[derive(Deserialize)]
+struct RxMetadata<'l> {
+    #[serde(borrow)]
+    gateway_ids: GatewayIdentifiers<'l>,
+    time: Option<&'l str>,
+    timestamp: Option<u32>,
+    #[serde(default)]
+    channel_index: u32,
+    rssi: Option<f64>,
+    channel_rssi: Option<f64>,
+    snr: Option<f64>,
+    #[serde(borrow)]
+    location: Option<Location<'l>>,
+}
+
+#[derive(Deserialize)]
+struct GatewayIdentifiers<'l> {
+    gateway_id: &'l str,
+}
+
+impl<'l> From<RxMetadata<'l>> for Reception<'l> {
+    fn from(rx: RxMetadata<'l>) -> Self {
+        Reception {
+            gtw_id: rx.gateway_ids.gateway_id,
+            time: rx.time.map(Cow::Borrowed),
+            timestamp: rx.timestamp,
+            channel: Some(rx.channel_index),
+            rf_chain: None,
+            // Older stack versions only report the channel RSSI:
+            rssi: rx.rssi.or(rx.channel_rssi),
+            snr: rx.snr,
+            longitude: rx.location.as_ref().map(|loc| loc.longitude),
+            latitude: rx.location.as_ref().map(|loc| loc.latitude),
+            altitude: rx.location.as_ref().and_then(|loc| loc.altitude),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Location<'l> {
+    longitude: f64,
+    latitude: f64,
+    altitude: Option<f64>,
+    source: Option<&'l str>,
+}
+
+impl<'l> From<UplinkMessageV3<'l>> for Uplink<'l> {
+    fn from(msg: UplinkMessageV3<'l>) -> Self {
+        let uplink = msg.uplink_message;
+        let settings = uplink.settings;
+        let lora = settings.data_rate.lora.as_ref();
+
+        let radio = RadioSettings {
+            frequency: settings.frequency,
+            modulation: if lora.is_some() {
+                Some("LORA")
+            } else if settings.data_rate.fsk.is_some() {
+                Some("FSK")
+            } else {
+                None
+            },
+            data_rate: lora
+                .map(|lora| format!("SF{:}BW{:}", lora.spreading_factor, lora.bandwidth / 1000)),
+            spreading_factor: lora.map(|lora| lora.spreading_factor),
+            bandwidth: lora.map(|lora| lora.bandwidth),
+            coding_rate: lora
+                .and_then(|lora| lora.coding_rate)
+                .or(settings.coding_rate),
+            airtime: uplink.consumed_airtime,
+        };
+
+        let gateways: Vec<Reception> = uplink
+            .rx_metadata
+            .into_iter()
+            .map(Reception::from)
+            .collect();
+
+        // The stack reports locations per source.
+        // "user" is the one that is configured in the device registry, "frm-payload" is decoded from the payload.
+        // If there is none, we fall back to the gateways.
+        let mut locations = uplink.locations;
+        let location = ["user", "frm-payload"]
+            .iter()
+            .find_map(|key| locations.remove_entry(key))
+            .or_else(|| locations.into_iter().next())
+            .map(|(key, loc)| UplinkLocation {
+                longitude: loc.longitude,
+                latitude: loc.latitude,
+                altitude: loc.altitude,
+                source: match (loc.source, key) {
+                    (Some(source), _) => LocationSource::from_v3(source),
+                    (None, "user") => LocationSource::Registry,
+                    (None, "frm-payload") => LocationSource::Gps,
+                    (None, _) => LocationSource::Unknown,
+                },
+            })
+            .or_else(|| UplinkLocation::from_gateways(&gateways));
+
+        Uplink {
+            app_id: msg.end_device_ids.application_ids.application_id,
+            dev_id: msg.end_device_ids.device_id,
+            hardware_serial: msg.end_device_ids.dev_eui,
+            port: uplink.f_port,
+            counter: uplink.f_cnt,
+            time: Cow::Borrowed(uplink.received_at),
+            location,
+            payload: uplink.frm_payload,
+            radio,
+            decoded: uplink.decoded_payload,
+            frame: None,
+            dev_addr: None,
+            mic_valid: None,
+            gateways,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An uplink message as published by The Things Stack (shortened):
+    const MESSAGE: &str = r#"{
+        "end_device_ids": {
+            "device_id": "eui-0004a30b001c0530",
+            "application_ids": {"application_id": "weather"},
+            "dev_eui": "0004A30B001C0530",
+            "join_eui": "0000000000000000",
+            "dev_addr": "260B1234"
+        },
+        "correlation_ids": ["as:up:01H0X4QJ9S7KZ8M2V3YB6T5N4R"],
+        "received_at": "2023-05-04T10:21:13.219Z",
+        "uplink_message": {
+            "session_key_id": "AYfG3c9aXgQyY0H1lQf0mA==",
+            "f_port": 2,
+            "f_cnt": 42,
+            "frm_payload": "AQI=",
+            "decoded_payload": {"temperature": 21.5},
+            "rx_metadata": [
+                {
+                    "gateway_ids": {"gateway_id": "gw-1", "eui": "A840411E6CF84150"},
+                    "time": "2023-05-04T10:21:13.001Z",
+                    "timestamp": 2040934975,
+                    "rssi": -97,
+                    "channel_rssi": -97,
+                    "snr": 7.5,
+                    "location": {"latitude": 52.52, "longitude": 13.40, "altitude": 34, "source": "SOURCE_REGISTRY"},
+                    "uplink_token": "ChIKEAoEZ3ctMRIIqEBBHmz4QVAQv8L2zAca",
+                    "channel_index": 3
+                },
+                {
+                    "gateway_ids": {"gateway_id": "gw-2"},
+                    "channel_rssi": -80,
+                    "snr": 9.25
+                }
+            ],
+            "settings": {
+                "data_rate": {"lora": {"bandwidth": 125000, "spreading_factor": 7, "coding_rate": "4/5"}},
+                "frequency": "868100000",
+                "timestamp": 2040934975
+            },
+            "received_at": "2023-05-04T10:21:13.012Z",
+            "consumed_airtime": "0.061696s",
+            "network_ids": {"net_id": "000013", "tenant_id": "ttn", "cluster_id": "eu1"}
+        }
+    }"#;
+
+    fn message(uplink_message: &str) -> String {
+        format!(
+            r#"{{"end_device_ids": {{"device_id": "node", "application_ids": {{"application_id": "app"}}}}, "uplink_message": {:}}}"#,
+            uplink_message
+        )
+    }
+
+    #[test]
+    fn parses_uplink_message() {
+        let uplink = TtnV3.parse(MESSAGE).unwrap();
+
+        assert_eq!(uplink.app_id, "weather");
+        assert_eq!(uplink.dev_id, "eui-0004a30b001c0530");
+        assert_eq!(uplink.hardware_serial, "0004A30B001C0530");
+        assert_eq!((uplink.port, uplink.counter), (2, 42));
+        // The time the network server received it (not the one of the application server):
+        assert_eq!(uplink.time, "2023-05-04T10:21:13.012Z");
+        assert_eq!(uplink.payload.as_slice(), [1, 2]);
+        assert_eq!(uplink.decoded.unwrap()["temperature"], 21.5);
+
+        // The frequency is a string and the airtime a duration:
+        assert_eq!(uplink.radio.frequency, Some(868_100_000));
+        assert_eq!(uplink.radio.airtime, Some(61_696_000));
+        assert_eq!(uplink.radio.modulation, Some("LORA"));
+        assert_eq!(uplink.radio.data_rate.as_deref(), Some("SF7BW125"));
+        assert_eq!(uplink.radio.coding_rate, Some("4/5"));
+
+        assert_eq!(uplink.gateways.len(), 2);
+        assert_eq!(uplink.gateways[0].timestamp, Some(2040934975));
+        assert_eq!(uplink.gateways[0].channel, Some(3));
+        assert_eq!(uplink.gateways[0].altitude, Some(34.0));
+        // Only the channel RSSI is reported and the channel index is omitted if it is 0:
+        assert_eq!(uplink.gateways[1].rssi, Some(-80.0));
+        assert_eq!(uplink.gateways[1].channel, Some(0));
+
+        // Without locations of its own, the device is located at the gateway with the best RSSI (that has a location):
+        let location = uplink.location.unwrap();
+        assert_eq!((location.longitude, location.latitude), (13.40, 52.52));
+        assert_eq!(location.source.as_str(), "gateway");
+    }
+
+    #[test]
+    fn defaults_omitted_fields() {
+        // An ABP device without a DevEUI sends an empty frame on port 0 with counter 0:
+        let line = message(r#"{"received_at": "2023-05-04T10:21:13.012Z"}"#);
+        let uplink = TtnV3.parse(&line).unwrap();
+
+        assert_eq!(uplink.hardware_serial, "");
+        assert_eq!((uplink.port, uplink.counter), (0, 0));
+        assert!(uplink.payload.as_slice().is_empty());
+        assert!(uplink.decoded.is_none());
+        assert!(uplink.location.is_none());
+        assert!(uplink.gateways.is_empty());
+        assert!(uplink.radio.frequency.is_none());
+        assert!(uplink.radio.airtime.is_none());
+        assert!(uplink.radio.modulation.is_none());
+    }
+
+    #[test]
+    fn prefers_user_locations() {
+        let locations = |locations: &str| {
+            let line = message(&format!(
+                r#"{{"received_at": "2023-05-04T10:21:13.012Z", "locations": {:}}}"#,
+                locations
+            ));
+            let location = TtnV3.parse(&line).unwrap().location.unwrap();
+
+            (location.longitude, location.source.as_str())
+        };
+
+        let user = r#""user": {"latitude": 1, "longitude": 1, "source": "SOURCE_REGISTRY"}"#;
+        let payload = r#""frm-payload": {"latitude": 2, "longitude": 2}"#;
+        let other = r#""a-geolocation": {"latitude": 3, "longitude": 3, "source": "SOURCE_WIFI_RSSI_GEOLOCATION"}"#;
+
+        assert_eq!(
+            locations(&format!("{{{:}, {:}, {:}}}", other, payload, user)),
+            (1.0, "registry")
+        );
+        assert_eq!(
+            locations(&format!("{{{:}, {:}}}", other, payload)),
+            (2.0, "gps")
+        );
+        assert_eq!(locations(&format!("{{{:}}}", other)), (3.0, "geolocation"));
+    }
+
+    #[test]
+    fn refuses_invalid_settings() {
+        for settings in [
+            r#"{"frequency": 868100000}"#,
+            r#"{"frequency": "868.1 MHz"}"#,
+        ] {
+            let line = message(&format!(
+                r#"{{"received_at": "2023-05-04T10:21:13.012Z", "settings": {:}}}"#,
+                settings
+            ));
+            assert!(TtnV3.parse(&line).is_err());
+        }
+
+        let line =
+            message(r#"{"received_at": "2023-05-04T10:21:13.012Z", "consumed_airtime": "61ms"}"#);
+        assert!(TtnV3.parse(&line).is_err());
+    }
+}
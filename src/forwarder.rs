@@ -0,0 +1,409 @@
+use crate::{debug, env_var, info, lorawan::DataFrame, Error, Input};
+use base64::engine::{general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, SecondsFormat};
+use serde::Deserialize;
+use serde_json::{json, Value as JSONValue};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::SyncSender;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// The configuration of the packet forwarder input mode.
+// It is read from the environment and only present if "TTN2SQLITE_FORWARDER_ADDR" (e.g. "0.0.0.0:1700") is set.
+pub struct ForwarderConfig {
+    addr: String,
+}
+
+impl ForwarderConfig {
+    pub fn from_env() -> Result<Option<ForwarderConfig>, Error> {
+        Ok(env_var("TTN2SQLITE_FORWARDER_ADDR").map(|addr| ForwarderConfig { addr }))
+    }
+}
+
+// The Semtech UDP protocol (versions 1 and 2):
+// Every datagram starts with the version, a random token (2 bytes) and an identifier.
+// Gateways push their receptions (and pull for downlinks, which we never send) with their EUI and a JSON object.
+const PROTOCOL_VERSIONS: [u8; 2] = [1, 2];
+const PUSH_DATA: u8 = 0x00;
+const PUSH_ACK: u8 = 0x01;
+const PULL_DATA: u8 = 0x02;
+const PULL_ACK: u8 = 0x04;
+const HEADER_SIZE: usize = 12;
+
+// How long we wait for other gateways to report a frame before it is forwarded:
+const DEDUP_WINDOW: Duration = Duration::from_millis(200);
+
+#[derive(Deserialize)]
+struct PushData {
+    #[serde(default)]
+    rxpk: Vec<Rxpk>,
+}
+
+// A single reception of a frame by a gateway:
+#[derive(Deserialize)]
+struct Rxpk {
+    // Only gateways with GPS know the time:
+    time: Option<String>,
+    tmst: Option<u32>,
+    // The frequency is given in MHz:
+    freq: Option<f64>,
+    chan: Option<u32>,
+    rfch: Option<u32>,
+    // The CRC status (1: OK, -1: failed, 0: no CRC):
+    stat: Option<i32>,
+    modu: Option<String>,
+    // A string like "SF7BW125" for LoRa, the bit rate for FSK:
+    datr: Option<JSONValue>,
+    codr: Option<String>,
+    rssi: Option<f64>,
+    lsnr: Option<f64>,
+    data: String,
+}
+
+// A frame that waits for further receptions:
+struct PendingFrame {
+    deadline: Instant,
+    received_at: String,
+    rxpk: Rxpk,
+    gateways: Vec<JSONValue>,
+}
+
+impl PendingFrame {
+    // Builds a raw frame message (as understood by "parse_uplink") with all receptions:
+    fn into_line(self) -> String {
+        let rxpk = self.rxpk;
+
+        json!({
+            "phy_payload": rxpk.data,
+            "time": rxpk.time.unwrap_or(self.received_at),
+            "frequency": rxpk.freq.map(|mhz| (mhz * 1e6).round() as u64),
+            "modulation": rxpk.modu,
+            "data_rate": rxpk.datr.map(|datr| match datr {
+                JSONValue::String(datr) => datr,
+                datr => datr.to_string(),
+            }),
+            "coding_rate": rxpk.codr,
+            "gateways": self.gateways,
+        })
+        .to_string()
+    }
+}
+
+// The current time as RFC 3339 string (for frames of gateways without GPS):
+fn now() -> String {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    DateTime::from_timestamp(since_epoch.as_secs() as i64, since_epoch.subsec_nanos())
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+// Receives the frames of packet forwarders and sends them as raw frame lines.
+// Receptions of the same frame by multiple gateways are merged into a single line.
+// Only uplink data frames with a valid CRC are forwarded, everything else (e.g. join requests) is dropped here.
+pub fn listen(config: &ForwarderConfig, sender: &SyncSender<Input>) -> Result<(), Error> {
+    let socket = UdpSocket::bind(config.addr.as_str())?;
+    info!("Listening for packet forwarders on {:}", config.addr);
+
+    let mut pending: HashMap<String, PendingFrame> = HashMap::new();
+    let mut buffer = [0; 65535];
+
+    loop {
+        // Wait for the next datagram, but not longer than until the next frame is due:
+        let timeout = pending
+            .values()
+            .map(|frame| frame.deadline)
+            .min()
+            .map(|deadline| {
+                deadline
+                    .saturating_duration_since(Instant::now())
+                    .max(Duration::from_millis(1))
+            });
+
+        socket.set_read_timeout(timeout)?;
+
+        match socket.recv_from(&mut buffer) {
+            Ok((size, peer)) => handle_datagram(&socket, &buffer[..size], peer, &mut pending),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        let now = Instant::now();
+        let due: Vec<String> = pending
+            .iter()
+            .filter(|(_, frame)| frame.deadline <= now)
+            .map(|(data, _)| data.clone())
+            .collect();
+
+        for data in due {
+            if let Some(frame) = pending.remove(&data) {
+                debug!(
+                    "Forwarding frame received by {:} gateways",
+                    frame.gateways.len()
+                );
+
+                // The receiver is gone, so we are shutting down:
+                if sender.send(Input::Line(frame.into_line(), None)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+// Acknowledges a datagram and collects the receptions in it:
+fn handle_datagram(
+    socket: &UdpSocket,
+    datagram: &[u8],
+    peer: SocketAddr,
+    pending: &mut HashMap<String, PendingFrame>,
+) {
+    let (version, token, identifier) = match datagram {
+        [version, token_high, token_low, identifier, ..]
+            if datagram.len() >= HEADER_SIZE && PROTOCOL_VERSIONS.contains(version) =>
+        {
+            (*version, [*token_high, *token_low], *identifier)
+        }
+        _ => {
+            println!("Ignoring invalid datagram from {:}", peer);
+            return;
+        }
+    };
+
+    let ack = match identifier {
+        PUSH_DATA => PUSH_ACK,
+        PULL_DATA => PULL_ACK,
+        // TX_ACK and everything else we don't know:
+        _ => return,
+    };
+
+    if let Err(err) = socket.send_to(&[version, token[0], token[1], ack], peer) {
+        println!(
+            "Error while acknowledging datagram from {:}: {:}",
+            peer, err
+        );
+    }
+
+    if identifier != PUSH_DATA {
+        return;
+    }
+
+    let gtw_id: String = datagram[4..HEADER_SIZE]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+
+    let push_data: PushData = match serde_json::from_slice(&datagram[HEADER_SIZE..]) {
+        Ok(push_data) => push_data,
+        Err(err) => {
+            println!(
+                "Ignoring invalid PUSH_DATA from gateway {:}:\n{:}",
+                gtw_id,
+                Error::from(err)
+            );
+            return;
+        }
+    };
+
+    for rxpk in push_data.rxpk {
+        if rxpk.stat.is_some_and(|stat| stat != 1) {
+            debug!("Dropping frame with a bad CRC from gateway {:}", gtw_id);
+            continue;
+        }
+
+        let is_data_up = BASE64
+            .decode(&rxpk.data)
+            .is_ok_and(|bytes| DataFrame::parse(bytes).is_ok());
+
+        if !is_data_up {
+            debug!(
+                "Dropping frame from gateway {:} (no uplink data frame)",
+                gtw_id
+            );
+            continue;
+        }
+
+        let reception = json!({
+            "gtw_id": gtw_id,
+            "time": rxpk.time,
+            "timestamp": rxpk.tmst,
+            "channel": rxpk.chan,
+            "rf_chain": rxpk.rfch,
+            "rssi": rxpk.rssi,
+            "snr": rxpk.lsnr,
+        });
+
+        match pending.get_mut(&rxpk.data) {
+            Some(frame) => {
+                // Prefer the time of a gateway with GPS over our own clock:
+                if frame.rxpk.time.is_none() {
+                    frame.rxpk.time = rxpk.time;
+                }
+
+                frame.gateways.push(reception);
+            }
+            None => {
+                pending.insert(
+                    rxpk.data.clone(),
+                    PendingFrame {
+                        deadline: Instant::now() + DEDUP_WINDOW,
+                        received_at: now(),
+                        rxpk,
+                        gateways: vec![reception],
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::Formats;
+
+    // An unconfirmed uplink of DevAddr 01020304 with FCnt 42 and FPort 1 (and a MIC that isn't checked here):
+    const DATA_UP: &str = "QAQDAgEAKgABqrsREiIz";
+    // A join request (which is no data frame):
+    const JOIN_REQUEST: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+
+    // The forwarder socket and the one of a gateway:
+    fn sockets() -> (UdpSocket, UdpSocket) {
+        let forwarder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let gateway = UdpSocket::bind("127.0.0.1:0").unwrap();
+        gateway
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        (forwarder, gateway)
+    }
+
+    fn datagram(identifier: u8, gateway: u8, json: &str) -> Vec<u8> {
+        [
+            &[2, 0x12, 0x34, identifier, 0, 0, 0, 0, 0, 0, 0, gateway][..],
+            json.as_bytes(),
+        ]
+        .concat()
+    }
+
+    fn receive_ack(gateway: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buffer = [0; 16];
+        let size = gateway.recv(&mut buffer).ok()?;
+
+        Some(buffer[..size].to_vec())
+    }
+
+    #[test]
+    fn acknowledges_push_and_pull_data() {
+        let (forwarder, gateway) = sockets();
+        let peer = gateway.local_addr().unwrap();
+        let mut pending = HashMap::new();
+
+        handle_datagram(
+            &forwarder,
+            &datagram(PUSH_DATA, 1, "{}"),
+            peer,
+            &mut pending,
+        );
+        assert_eq!(receive_ack(&gateway), Some(vec![2, 0x12, 0x34, PUSH_ACK]));
+
+        handle_datagram(&forwarder, &datagram(PULL_DATA, 1, ""), peer, &mut pending);
+        assert_eq!(receive_ack(&gateway), Some(vec![2, 0x12, 0x34, PULL_ACK]));
+
+        // TX_ACK and datagrams of unknown versions are not acknowledged:
+        handle_datagram(&forwarder, &datagram(0x05, 1, ""), peer, &mut pending);
+        let mut unknown = datagram(PUSH_DATA, 1, "{}");
+        unknown[0] = 3;
+        handle_datagram(&forwarder, &unknown, peer, &mut pending);
+        assert_eq!(receive_ack(&gateway), None);
+
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn drops_bad_and_other_frames() {
+        let (forwarder, gateway) = sockets();
+        let peer = gateway.local_addr().unwrap();
+        let mut pending = HashMap::new();
+
+        let push_data = format!(
+            r#"{{"rxpk": [{{"stat": -1, "data": "{:}"}}, {{"stat": 1, "data": "{:}"}}, {{"stat": 1, "data": "not base64"}}]}}"#,
+            DATA_UP, JOIN_REQUEST
+        );
+        handle_datagram(
+            &forwarder,
+            &datagram(PUSH_DATA, 1, &push_data),
+            peer,
+            &mut pending,
+        );
+
+        // The datagram is acknowledged nevertheless:
+        assert_eq!(receive_ack(&gateway), Some(vec![2, 0x12, 0x34, PUSH_ACK]));
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn merges_receptions_of_the_same_frame() {
+        let (forwarder, gateway) = sockets();
+        let peer = gateway.local_addr().unwrap();
+        let mut pending = HashMap::new();
+
+        // Only the second gateway has GPS:
+        let reception = |extra: &str| {
+            format!(
+                r#"{{"rxpk": [{{"tmst": 2040934975, "freq": 868.1, "chan": 3, "rfch": 1, "stat": 1, "modu": "LORA", "datr": "SF7BW125", "codr": "4/5", "rssi": -97, "lsnr": 7.5, "data": "{:}"{:}}}]}}"#,
+                DATA_UP, extra
+            )
+        };
+
+        let start = Instant::now();
+        handle_datagram(
+            &forwarder,
+            &datagram(PUSH_DATA, 1, &reception("")),
+            peer,
+            &mut pending,
+        );
+        handle_datagram(
+            &forwarder,
+            &datagram(
+                PUSH_DATA,
+                2,
+                &reception(r#", "time": "2023-05-04T10:21:13.001Z""#),
+            ),
+            peer,
+            &mut pending,
+        );
+
+        assert_eq!(pending.len(), 1);
+        let frame = pending.remove(DATA_UP).unwrap();
+        assert!(frame.deadline <= start + DEDUP_WINDOW + Duration::from_millis(50));
+
+        // The merged line is understood by the raw frame format:
+        let line = frame.into_line();
+        let (format, uplink) = Formats::default().parse(&line).unwrap();
+
+        assert_eq!(format, "raw-frame");
+        assert_eq!(uplink.dev_addr, Some(0x01020304));
+        assert_eq!((uplink.port, uplink.counter), (1, 42));
+        assert_eq!(uplink.time, "2023-05-04T10:21:13.001Z");
+        assert_eq!(uplink.radio.frequency, Some(868_100_000));
+        assert_eq!(uplink.radio.data_rate.as_deref(), Some("SF7BW125"));
+        assert_eq!(uplink.radio.coding_rate, Some("4/5"));
+
+        let gateways: Vec<_> = uplink
+            .gateways
+            .iter()
+            .map(|gtw| (gtw.gtw_id, gtw.timestamp, gtw.channel, gtw.rssi))
+            .collect();
+        assert_eq!(
+            gateways,
+            [
+                ("0000000000000001", Some(2040934975), Some(3), Some(-97.0)),
+                ("0000000000000002", Some(2040934975), Some(3), Some(-97.0))
+            ]
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use crate::decode::Measurement;
+use crate::{Error, Uplink};
+use serde::Deserialize;
+use std::fs;
+
+// The file with the payload layouts of our own sensors, written in TOML:
+//
+// [[layout]]
+// app_id = "my-app"  # optional, matches all applications if missing
+// dev_id = "node-1"  # optional, matches all devices if missing
+// port = 2
+// fields = [
+//     { name = "temperature", offset = 0, bits = 16, signed = true, scale = 0.01, unit = "°C" },
+//     { name = "battery_low", offset = 2, bits = 1, shift = 7 },
+// ]
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayoutFile {
+    #[serde(default)]
+    layout: Vec<Layout>,
+}
+
+// Describes the payloads of the uplinks on a single port:
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    app_id: Option<String>,
+    dev_id: Option<String>,
+    port: u32,
+    fields: Vec<Field>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum Endianness {
+    #[default]
+    Big,
+    Little,
+}
+
+// A single value in a payload.
+// It is read as integer from the bytes starting at "offset" (in the given endianness).
+// Then it is shifted right by "shift" bits and masked to "bits" bits, so values don't have to be byte-aligned.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Field {
+    name: String,
+    offset: usize,
+    bits: u32,
+    #[serde(default)]
+    shift: u32,
+    #[serde(default)]
+    signed: bool,
+    #[serde(default)]
+    endianness: Endianness,
+    #[serde(default = "Field::default_scale")]
+    scale: f64,
+    unit: Option<String>,
+}
+
+impl Field {
+    fn default_scale() -> f64 {
+        1.0
+    }
+
+    // The number of bytes the value is read from:
+    fn size(&self) -> usize {
+        (self.shift + self.bits).div_ceil(8) as usize
+    }
+
+    fn read(&self, payload: &[u8]) -> Result<f64, String> {
+        let bytes = self
+            .offset
+            .checked_add(self.size())
+            .and_then(|end| payload.get(self.offset..end))
+            .ok_or_else(|| format!("payload is too short for field \"{:}\"", self.name))?;
+
+        let raw = match self.endianness {
+            Endianness::Big => bytes
+                .iter()
+                .fold(0_u64, |acc, byte| (acc << 8) | u64::from(*byte)),
+            Endianness::Little => bytes
+                .iter()
+                .rev()
+                .fold(0_u64, |acc, byte| (acc << 8) | u64::from(*byte)),
+        };
+
+        // Move the value to the top of the integer, so the sign can be extended by shifting it back:
+        let unused = 64 - self.bits;
+        let value = (raw >> self.shift) << unused;
+
+        let value = if self.signed {
+            ((value as i64) >> unused) as f64
+        } else {
+            (value >> unused) as f64
+        };
+
+        Ok(value * self.scale)
+    }
+}
+
+impl Layout {
+    pub fn matches(&self, uplink: &Uplink) -> bool {
+        self.port == uplink.port
+            && self.app_id.as_deref().is_none_or(|id| id == uplink.app_id)
+            && self.dev_id.as_deref().is_none_or(|id| id == uplink.dev_id)
+    }
+
+    pub fn decode(&self, payload: &[u8]) -> Result<Vec<Measurement>, String> {
+        self.fields
+            .iter()
+            .map(|field| {
+                Ok(Measurement {
+                    channel: None,
+                    kind: field.name.clone(),
+                    value: field.read(payload)?,
+                    unit: field.unit.clone(),
+                })
+            })
+            .collect()
+    }
+}
+
+// Loads the layouts from the given file.
+// They are checked here, so a broken field is noticed at startup (and not for every uplink).
+pub fn load(path: &str) -> Result<Vec<Layout>, Error> {
+    let content = fs::read_to_string(path)?;
+    let file: LayoutFile = toml::from_str(&content)
+        .map_err(|err| Error::Config(format!("invalid layout file \"{:}\": {:}", path, err)))?;
+
+    for layout in &file.layout {
+        for field in &layout.fields {
+            // A huge shift must not wrap around:
+            if field.bits == 0
+                || field
+                    .shift
+                    .checked_add(field.bits)
+                    .is_none_or(|bits| bits > 64)
+            {
+                return Err(Error::Config(format!(
+                    "field \"{:}\" on port {:} must have between 1 and 64 bits (including its shift)",
+                    field.name, layout.port
+                )));
+            }
+        }
+    }
+
+    Ok(file.layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn layout(fields: &str) -> Layout {
+        toml::from_str(&format!("port = 1\nfields = [{:}]", fields)).unwrap()
+    }
+
+    fn decoded(layout: &Layout, payload: &[u8]) -> Vec<(String, f64)> {
+        layout
+            .decode(payload)
+            .unwrap()
+            .into_iter()
+            .map(|measurement| (measurement.kind, measurement.value))
+            .collect()
+    }
+
+    #[test]
+    fn decodes_signed_fields() {
+        let layout = layout(
+            r#"{ name = "temperature", offset = 0, bits = 16, signed = true, scale = 0.5 },
+            { name = "raw", offset = 0, bits = 16 }"#,
+        );
+
+        assert_eq!(
+            decoded(&layout, &[0xff, 0x38]),
+            [
+                (String::from("temperature"), -100.0),
+                (String::from("raw"), 65336.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_little_endian_fields() {
+        let layout = layout(
+            r#"{ name = "little", offset = 1, bits = 24, endianness = "little" },
+            { name = "big", offset = 1, bits = 24 }"#,
+        );
+
+        assert_eq!(
+            decoded(&layout, &[0x00, 0x56, 0x34, 0x12]),
+            [
+                (String::from("little"), 1193046.0),
+                (String::from("big"), 5649426.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_shifted_fields() {
+        let layout = layout(
+            r#"{ name = "flag", offset = 0, bits = 1, shift = 7 },
+            { name = "mode", offset = 0, bits = 3, shift = 2 },
+            { name = "offset", offset = 0, bits = 2, signed = true },
+            { name = "across", offset = 0, bits = 8, shift = 4 }"#,
+        );
+
+        assert_eq!(
+            decoded(&layout, &[0b1001_1110, 0b1010_0101]),
+            [
+                (String::from("flag"), 1.0),
+                (String::from("mode"), 7.0),
+                (String::from("offset"), -2.0),
+                (String::from("across"), 0b1110_1010 as f64)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_short_payloads() {
+        let layout = layout(r#"{ name = "value", offset = 1, bits = 16 }"#);
+
+        assert!(layout.decode(&[0x00, 0x01]).is_err());
+    }
+
+    #[test]
+    fn refuses_fields_beyond_64_bits() {
+        let path = env::temp_dir().join(format!("ttn2sqlite-layout-{:}.toml", std::process::id()));
+
+        for (shift, bits) in [(0, 0), (0, 65), (60, 8), (u32::MAX, 8)] {
+            fs::write(
+                &path,
+                format!(
+                    "[[layout]]\nport = 1\nfields = [{{ name = \"value\", offset = 0, bits = {:}, shift = {:} }}]",
+                    bits, shift
+                ),
+            )
+            .unwrap();
+
+            assert!(load(path.to_str().unwrap()).is_err());
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+}
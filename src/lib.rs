@@ -0,0 +1,730 @@
+pub mod batch;
+pub mod decode;
+pub mod export;
+pub mod formats;
+pub mod forwarder;
+mod layout;
+pub mod lorawan;
+mod lpp;
+pub mod mqtt;
+mod plugin;
+pub mod prune;
+pub mod reprocess;
+pub mod schema;
+mod script;
+pub mod stats;
+pub mod webhook;
+
+use chrono::DateTime;
+use decode::{Decoded, Decoders};
+use formats::Formats;
+use lorawan::{DataFrame, Keys};
+use rumqttc::ClientError as MqttError;
+use rusqlite::{Connection, Error as SQLiteError, OptionalExtension, Statement, ToSql};
+use serde_json::{Error as JSONError, Value as JSONValue};
+use std::error::Error as StdError;
+use std::io::{Error as IOError, ErrorKind as IOErrorKind};
+use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::{borrow::Cow, convert::From, env, fmt, str::FromStr, str::Utf8Error};
+
+pub use formats::deserialize_payload;
+
+// How much is printed besides errors and warnings (which are always printed):
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    // What happens to the DB and to every message:
+    Normal,
+    // Also the batches and what input sources drop on the way:
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+// Prints unless we are asked to be quiet:
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::verbosity() >= $crate::Verbosity::Normal {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Prints only if we are asked to be verbose:
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::verbosity() >= $crate::Verbosity::Verbose {
+            println!($($arg)*);
+        }
+    };
+}
+
+// A universal error type for everything that can go wrong here:
+pub enum Error {
+    Io(IOError),
+    Json(JSONError),
+    SQLite(SQLiteError),
+    Mqtt(MqttError),
+    Config(String),
+    Schema(String),
+    Time(String),
+    Decode(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        <Error as fmt::Debug>::fmt(self, f)
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "IO error ({:})", err),
+            Error::Json(err) => write!(f, "JSON error ({:})", err),
+            Error::SQLite(err) => write!(f, "SQLite error ({:})", err),
+            Error::Mqtt(err) => write!(f, "MQTT error ({:})", err),
+            Error::Config(msg) => write!(f, "Configuration error ({:})", msg),
+            Error::Schema(msg) => write!(f, "Schema error ({:})", msg),
+            Error::Time(msg) => write!(f, "Time error ({:})", msg),
+            Error::Decode(msg) => write!(f, "Decode error ({:})", msg),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Json(err) => Some(err),
+            Error::SQLite(err) => Some(err),
+            Error::Mqtt(err) => Some(err),
+            Error::Config(_) | Error::Schema(_) | Error::Time(_) | Error::Decode(_) => None,
+        }
+    }
+}
+
+impl Error {
+    // The name of the variant, e.g. to store it along with rejected messages:
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "Io",
+            Error::Json(_) => "Json",
+            Error::SQLite(_) => "SQLite",
+            Error::Mqtt(_) => "Mqtt",
+            Error::Config(_) => "Config",
+            Error::Schema(_) => "Schema",
+            Error::Time(_) => "Time",
+            Error::Decode(_) => "Decode",
+        }
+    }
+
+    // The message of the underlying error:
+    pub fn message(&self) -> String {
+        match self {
+            Error::Io(err) => err.to_string(),
+            Error::Json(err) => err.to_string(),
+            Error::SQLite(err) => err.to_string(),
+            Error::Mqtt(err) => err.to_string(),
+            Error::Config(msg) | Error::Schema(msg) | Error::Time(msg) | Error::Decode(msg) => {
+                msg.clone()
+            }
+        }
+    }
+}
+
+impl From<IOError> for Error {
+    fn from(err: IOError) -> Self {
+        Error::Io(err)
+    }
+}
+
+// Messages must be valid UTF-8 (like every JSON document):
+impl From<Utf8Error> for Error {
+    fn from(err: Utf8Error) -> Self {
+        Error::Io(IOError::new(IOErrorKind::InvalidData, err))
+    }
+}
+
+impl From<JSONError> for Error {
+    fn from(err: JSONError) -> Self {
+        Error::Json(err)
+    }
+}
+
+impl From<SQLiteError> for Error {
+    fn from(err: SQLiteError) -> Self {
+        Error::SQLite(err)
+    }
+}
+
+impl From<MqttError> for Error {
+    fn from(err: MqttError) -> Self {
+        Error::Mqtt(err)
+    }
+}
+
+// What has become of a message once its transaction has ended:
+pub enum Delivery<'e> {
+    // It has been stored (or skipped as duplicate):
+    Stored,
+    // It has failed, but its line has been stored in "rejected":
+    Rejected(&'e Error),
+    // Nothing has been stored because the transaction couldn't be committed:
+    Failed(&'e Error),
+}
+
+// Invoked once it is known whether a message has been stored (i.e. committed) or not:
+pub type Completion = Box<dyn FnOnce(Delivery) + Send>;
+
+// The input sources run on their own threads and send us what they receive:
+pub enum Input {
+    // A JSON-encoded message, optionally with a completion for the source:
+    Line(String, Option<Completion>),
+    // A message that isn't valid UTF-8 (and can only be kept in "rejected"), along with its bytes:
+    Invalid(FromUtf8Error, Option<Completion>),
+    // The source has been exhausted (or we are asked to shut down):
+    End(Result<(), Error>),
+}
+
+impl Input {
+    // Wraps a message that has been received as bytes:
+    pub fn from_bytes(bytes: Vec<u8>, completion: Option<Completion>) -> Input {
+        match String::from_utf8(bytes) {
+            Ok(line) => Input::Line(line, completion),
+            Err(err) => Input::Invalid(err, completion),
+        }
+    }
+}
+
+// Everything the command line doesn't cover (e.g. the MQTT broker or the decoders) is configured by environment variables.
+// Empty variables are treated as if they were not set.
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn env_parse<T>(name: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    env_var(name)
+        .map(|value| {
+            value.parse().map_err(|err| {
+                Error::Config(format!(
+                    "invalid value \"{:}\" for {:}: {:}",
+                    value, name, err
+                ))
+            })
+        })
+        .transpose()
+}
+
+fn env_flag(name: &str) -> bool {
+    matches!(env_var(name).as_deref(), Some("1" | "true" | "yes" | "on"))
+}
+
+// The normalized uplink record.
+// Every supported input format is mapped into this before it is stored in our DB.
+pub struct Uplink<'l> {
+    pub app_id: &'l str,
+    pub dev_id: &'l str,
+    pub hardware_serial: &'l str,
+    pub port: u32,
+    pub counter: u32,
+    pub time: Cow<'l, str>,
+    pub location: Option<UplinkLocation>,
+    pub payload: Payload,
+    pub radio: RadioSettings<'l>,
+
+    // The fields the payload formatter of the network server has decoded (if there is one):
+    pub decoded: Option<JSONValue>,
+
+    // Raw frames still have to be attributed to a device and decrypted (which sets the fields above).
+    // Their MIC is verified on the way:
+    pub frame: Option<DataFrame>,
+    pub dev_addr: Option<u32>,
+    pub mic_valid: Option<bool>,
+
+    // Every gateway that received this uplink:
+    pub gateways: Vec<Reception<'l>>,
+}
+
+// The location of the device that sent an uplink:
+pub struct UplinkLocation {
+    pub longitude: f64,
+    pub latitude: f64,
+    pub altitude: Option<f64>,
+    pub source: LocationSource,
+}
+
+impl UplinkLocation {
+    // Approximates the device location by the location of the gateway that received the uplink with the best RSSI:
+    fn from_gateways(gateways: &[Reception]) -> Option<UplinkLocation> {
+        gateways
+            .iter()
+            .filter(|gtw| gtw.longitude.is_some() && gtw.latitude.is_some())
+            .max_by(|a, b| {
+                let rssi = |gtw: &&Reception| gtw.rssi.unwrap_or(f64::NEG_INFINITY);
+                rssi(a).total_cmp(&rssi(b))
+            })
+            .map(|gtw| UplinkLocation {
+                longitude: gtw.longitude.unwrap(),
+                latitude: gtw.latitude.unwrap(),
+                altitude: gtw.altitude,
+                source: LocationSource::Gateway,
+            })
+    }
+}
+
+// Where the location of a device comes from:
+#[derive(Clone, Copy)]
+pub enum LocationSource {
+    // Configured in the device registry of the network server:
+    Registry,
+    // Reported by the device itself (e.g. from a GPS receiver in the payload):
+    Gps,
+    // Computed by the network (e.g. via TDOA or RSSI):
+    Geolocation,
+    // Approximated by the location of a receiving gateway:
+    Gateway,
+    Unknown,
+}
+
+impl LocationSource {
+    // Maps the "location_source" field of TTN v2:
+    fn from_v2(source: &str) -> LocationSource {
+        match source {
+            "registry" => LocationSource::Registry,
+            "gps" => LocationSource::Gps,
+            _ => LocationSource::Unknown,
+        }
+    }
+
+    // Maps the "source" field of a TTN v3 location (e.g. "SOURCE_REGISTRY"):
+    fn from_v3(source: &str) -> LocationSource {
+        match source {
+            "SOURCE_REGISTRY" => LocationSource::Registry,
+            "SOURCE_GPS" => LocationSource::Gps,
+            _ if source.ends_with("_GEOLOCATION") => LocationSource::Geolocation,
+            _ => LocationSource::Unknown,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LocationSource::Registry => "registry",
+            LocationSource::Gps => "gps",
+            LocationSource::Geolocation => "geolocation",
+            LocationSource::Gateway => "gateway",
+            LocationSource::Unknown => "unknown",
+        }
+    }
+}
+
+// The radio settings an uplink was transmitted with.
+// Spreading factor and bandwidth (in Hz) are only present for LoRa.
+pub struct RadioSettings<'l> {
+    pub frequency: Option<u64>,
+    pub modulation: Option<&'l str>,
+    pub data_rate: Option<String>,
+    pub spreading_factor: Option<u32>,
+    pub bandwidth: Option<u32>,
+    pub coding_rate: Option<&'l str>,
+    pub airtime: Option<u64>,
+}
+
+impl<'l> RadioSettings<'l> {
+    // Splits a LoRa data rate like "SF7BW125" into spreading factor and bandwidth (in Hz):
+    fn parse_lora_data_rate(data_rate: &str) -> Option<(u32, u32)> {
+        let (sf, bw) = data_rate.strip_prefix("SF")?.split_once("BW")?;
+
+        Some((sf.parse().ok()?, bw.parse::<u32>().ok()? * 1000))
+    }
+}
+
+// A single reception of an uplink by a gateway.
+// Everything except the gateway ID is optional because not every gateway / network server reports it.
+pub struct Reception<'l> {
+    pub gtw_id: &'l str,
+    pub time: Option<Cow<'l, str>>,
+    pub timestamp: Option<u32>,
+    pub channel: Option<u32>,
+    pub rf_chain: Option<u32>,
+    pub rssi: Option<f64>,
+    pub snr: Option<f64>,
+    pub longitude: Option<f64>,
+    pub latitude: Option<f64>,
+    pub altitude: Option<f64>,
+}
+
+pub struct Payload {
+    bytes: [u8; Payload::MAX_PAYLOAD_SIZE],
+    size: usize,
+}
+
+impl Payload {
+    // The maximum payload size in bytes, as defined by TTN:
+    pub const MAX_PAYLOAD_SIZE: usize = 512;
+
+    pub fn empty() -> Payload {
+        Payload {
+            bytes: [0; Payload::MAX_PAYLOAD_SIZE],
+            size: 0,
+        }
+    }
+
+    // Takes at most Payload::MAX_PAYLOAD_SIZE bytes:
+    pub fn from_slice(bytes: &[u8]) -> Payload {
+        let mut payload = Payload::empty();
+        payload.size = bytes.len().min(Payload::MAX_PAYLOAD_SIZE);
+        payload.bytes[..payload.size].copy_from_slice(&bytes[..payload.size]);
+
+        payload
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes[0..self.size]
+    }
+}
+
+// This function parses an RFC3339 timestamp (e.g. "2020-01-01T12:00:00.123456789Z") into nanoseconds since the Unix epoch.
+pub fn parse_time(time: &str) -> Result<i64, Error> {
+    DateTime::parse_from_rfc3339(time)
+        .map_err(|err| err.to_string())
+        .and_then(|time| {
+            time.timestamp_nanos_opt()
+                .ok_or_else(|| String::from("out of range"))
+        })
+        .map_err(|err| Error::Time(format!("invalid time \"{:}\": {:}", time, err)))
+}
+
+// The prepared statements that are needed to store a message:
+struct Statements<'c> {
+    upsert_application: Statement<'c>,
+    upsert_device: Statement<'c>,
+    insert_data: Statement<'c>,
+    insert_gateway: Statement<'c>,
+    insert_measurement: Statement<'c>,
+    select_last_counter: Statement<'c>,
+}
+
+impl<'c> Statements<'c> {
+    fn prepare(db_connection: &'c Connection) -> Result<Statements<'c>, Error> {
+        // Applications and devices are created when they are seen for the first time.
+        // Their first-seen / last-seen times follow the uplink times (not the wall clock), so backfills are handled correctly.
+        let upsert_application = db_connection.prepare(
+            "INSERT INTO applications (app_id, first_seen_ns, last_seen_ns) VALUES (?1, ?2, ?2)
+            	ON CONFLICT (app_id) DO UPDATE SET
+            		first_seen_ns = MIN(IFNULL(first_seen_ns, excluded.first_seen_ns), excluded.first_seen_ns),
+            		last_seen_ns = MAX(IFNULL(last_seen_ns, excluded.last_seen_ns), excluded.last_seen_ns)
+            	RETURNING id",
+        )?;
+
+        // The location of a device is only replaced by a newer one.
+        let upsert_device = db_connection.prepare(
+            "INSERT INTO devices
+            	(application_id, dev_id, hardware_serial, first_seen_ns, last_seen_ns,
+            	lon, lat, alt, location_source, location_time_ns)
+            	VALUES (?1, ?2, ?3, ?4, ?4, ?5, ?6, ?7, ?8, CASE WHEN ?5 IS NOT NULL THEN ?4 END)
+            	ON CONFLICT (application_id, dev_id) DO UPDATE SET
+            		hardware_serial = CASE WHEN excluded.last_seen_ns >= IFNULL(last_seen_ns, excluded.last_seen_ns)
+            			THEN excluded.hardware_serial ELSE hardware_serial END,
+            		first_seen_ns = MIN(IFNULL(first_seen_ns, excluded.first_seen_ns), excluded.first_seen_ns),
+            		last_seen_ns = MAX(IFNULL(last_seen_ns, excluded.last_seen_ns), excluded.last_seen_ns),
+            		lon = CASE WHEN excluded.location_time_ns >= IFNULL(location_time_ns, excluded.location_time_ns)
+            			THEN excluded.lon ELSE lon END,
+            		lat = CASE WHEN excluded.location_time_ns >= IFNULL(location_time_ns, excluded.location_time_ns)
+            			THEN excluded.lat ELSE lat END,
+            		alt = CASE WHEN excluded.location_time_ns >= IFNULL(location_time_ns, excluded.location_time_ns)
+            			THEN excluded.alt ELSE alt END,
+            		location_source = CASE WHEN excluded.location_time_ns >= IFNULL(location_time_ns, excluded.location_time_ns)
+            			THEN excluded.location_source ELSE location_source END,
+            		location_time_ns = CASE WHEN excluded.location_time_ns >= IFNULL(location_time_ns, excluded.location_time_ns)
+            			THEN excluded.location_time_ns ELSE location_time_ns END
+            	RETURNING id",
+        )?;
+
+        let insert_data = db_connection.prepare(
+            "INSERT INTO data
+            	(device_id, port, counter, time, time_ns, lon, lat, alt, location_source, payload,
+            	frequency, modulation, data_rate, spreading_factor, bandwidth, coding_rate, airtime, decoded,
+            	mic_valid, decode_error, decoder_fields, dev_addr)
+            	VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            	ON CONFLICT DO NOTHING RETURNING id",
+        )?;
+
+        let insert_gateway = db_connection.prepare(
+            "INSERT INTO gateways
+            	(data_id, gtw_id, time, timestamp, channel, rf_chain, rssi, snr, lon, lat, alt)
+            	VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+
+        let insert_measurement = db_connection.prepare(
+            "INSERT INTO measurements (data_id, channel, type, value, unit) VALUES (?, ?, ?, ?, ?)",
+        )?;
+
+        // The counter of the latest uplink of a device, to restore the upper bits of frame counters:
+        let select_last_counter = db_connection.prepare(
+            "SELECT data.counter FROM data
+            	JOIN devices ON devices.id = data.device_id
+            	JOIN applications ON applications.id = devices.application_id
+            	WHERE applications.app_id = ? AND devices.dev_id = ?
+            	ORDER BY data.time_ns DESC LIMIT 1",
+        )?;
+
+        Ok(Statements {
+            upsert_application,
+            upsert_device,
+            insert_data,
+            insert_gateway,
+            insert_measurement,
+            select_last_counter,
+        })
+    }
+}
+
+// What happened to a message that has been processed successfully:
+#[derive(PartialEq)]
+pub enum Outcome {
+    Inserted,
+    // The dedup key matches an uplink that is already in the DB:
+    Duplicate,
+}
+
+// Stores uplinks in a DB whose schema has been set up (see "schema::setup").
+// It bundles everything that is needed on the way: the input formats, the payload decoders and the session keys of raw frames.
+pub struct Ingestor<'c> {
+    db_connection: &'c Connection,
+    statements: Statements<'c>,
+    formats: Formats,
+    decoders: Decoders,
+    keys: Keys,
+}
+
+impl<'c> Ingestor<'c> {
+    pub fn new(
+        db_connection: &'c Connection,
+        formats: Formats,
+        decoders: Decoders,
+        keys: Keys,
+    ) -> Result<Ingestor<'c>, Error> {
+        Ok(Ingestor {
+            db_connection,
+            statements: Statements::prepare(db_connection)?,
+            formats,
+            decoders,
+            keys,
+        })
+    }
+
+    // Configures formats, decoders and keys from the environment (like the CLI does):
+    pub fn from_env(db_connection: &'c Connection) -> Result<Ingestor<'c>, Error> {
+        Ingestor::new(
+            db_connection,
+            Formats::from_env()?,
+            Decoders::from_env()?,
+            Keys::from_env()?,
+        )
+    }
+
+    // Deserializes a JSON-encoded message (in any of our formats) and stores it.
+    // Uplinks with a time we can't understand are rejected, so they can't mess up time-based queries.
+    pub fn ingest_line(&mut self, line: &str) -> Result<Outcome, Error> {
+        let (format, msg) = self.formats.parse(line)?;
+        self.store(format, msg)
+    }
+
+    // Stores a message that has been parsed by the caller:
+    pub fn ingest(&mut self, msg: Uplink) -> Result<Outcome, Error> {
+        self.store("parsed", msg)
+    }
+
+    fn store(&mut self, format: &str, msg: Uplink) -> Result<Outcome, Error> {
+        // Raw frames get the IDs of their session (which doesn't live as long as the message) or of their DevAddr:
+        let dev_addr_id;
+        let mut msg = msg;
+        let mut encrypted = false;
+        let time_ns = parse_time(&msg.time)?;
+
+        // Raw frames are decrypted with the keys of their session:
+        if let Some(frame) = msg.frame.take() {
+            let select_last_counter = &mut self.statements.select_last_counter;
+            let mut last_counter = |app_id: &str, dev_id: &str| -> Result<Option<u32>, Error> {
+                let last_counter = select_last_counter
+                    .query_row((app_id, dev_id), |row| row.get(0))
+                    .optional()?;
+
+                Ok(last_counter)
+            };
+
+            match self.keys.open(&frame, |session| {
+                last_counter(&session.app_id, &session.dev_id)
+            })? {
+                Some(opened) => {
+                    if opened.mic_valid == Some(false) {
+                        println!(
+                            "MIC verification failed (DevAddr: {:08X}, counter: {:})",
+                            frame.dev_addr, opened.f_cnt
+                        );
+                    }
+
+                    msg.app_id = &opened.session.app_id;
+                    msg.dev_id = &opened.session.dev_id;
+                    msg.hardware_serial = &opened.session.dev_eui;
+                    msg.counter = opened.f_cnt;
+                    msg.payload = Payload::from_slice(&opened.payload);
+                    msg.mic_valid = opened.mic_valid;
+                }
+
+                // Without session keys, the frame is stored anyway.
+                // It is attributed to a device named after its DevAddr (without application) and its payload stays encrypted.
+                None => {
+                    info!(
+                        "No session keys for DevAddr {:08X}, storing its payload encrypted",
+                        frame.dev_addr
+                    );
+
+                    dev_addr_id = format!("{:08X}", frame.dev_addr);
+                    msg.dev_id = &dev_addr_id;
+                    msg.counter =
+                        lorawan::restore_f_cnt(frame.f_cnt, last_counter("", msg.dev_id)?);
+                    msg.payload = Payload::from_slice(frame.frm_payload());
+                    encrypted = true;
+                }
+            }
+        }
+
+        // Print some info about it:
+        info!("Received {:} uplink message (appID: \"{:}\", deviceID: \"{:}\", time: \"{:}\", payload: {:} bytes, gateways: {:})", format, msg.app_id, msg.dev_id, msg.time, msg.payload.size, msg.gateways.len());
+
+        // Look up (or create) the application and device it belongs to:
+        let application_id: i64 = self
+            .statements
+            .upsert_application
+            .query_row((msg.app_id, time_ns), |row| row.get(0))?;
+
+        let device_id: i64 = self.statements.upsert_device.query_row(
+            [
+                &application_id as &dyn ToSql,
+                &msg.dev_id,
+                &msg.hardware_serial,
+                &time_ns,
+                &msg.location.as_ref().map(|loc| loc.longitude),
+                &msg.location.as_ref().map(|loc| loc.latitude),
+                &msg.location.as_ref().and_then(|loc| loc.altitude),
+                &msg.location.as_ref().map(|loc| loc.source.as_str()),
+            ],
+            |row| row.get(0),
+        )?;
+
+        // Decode the payload (if a decoder is configured for it and it isn't encrypted).
+        // A payload that doesn't match its decoder doesn't keep the uplink out of "data", the error is stored along with it instead:
+        let decoded = if encrypted {
+            Ok(Decoded::default())
+        } else {
+            self.decoders.decode(&msg)
+        };
+
+        let (decoded, decode_error) = match decoded {
+            Ok(decoded) => (decoded, None),
+            Err(err) => {
+                println!(
+                    "Error while decoding payload (deviceID: \"{:}\", counter: {:}):\n{:}",
+                    msg.dev_id, msg.counter, err
+                );
+                (Decoded::default(), Some(err.message()))
+            }
+        };
+
+        // Store it into our database.
+        // If it violates the dedup key, nothing is inserted (and nothing is returned).
+        let data_id: Option<i64> = self
+            .statements
+            .insert_data
+            .query_row(
+                [
+                    &device_id as &dyn ToSql,
+                    &msg.port,
+                    &msg.counter,
+                    &msg.time,
+                    &time_ns,
+                    &msg.location.as_ref().map(|loc| loc.longitude),
+                    &msg.location.as_ref().map(|loc| loc.latitude),
+                    &msg.location.as_ref().and_then(|loc| loc.altitude),
+                    &msg.location.as_ref().map(|loc| loc.source.as_str()),
+                    &msg.payload.as_slice(),
+                    &msg.radio.frequency,
+                    &msg.radio.modulation,
+                    &msg.radio.data_rate,
+                    &msg.radio.spreading_factor,
+                    &msg.radio.bandwidth,
+                    &msg.radio.coding_rate,
+                    &msg.radio.airtime,
+                    &msg.decoded.as_ref().map(JSONValue::to_string),
+                    &msg.mic_valid,
+                    &decode_error,
+                    &decoded.fields.as_ref().map(JSONValue::to_string),
+                    &msg.dev_addr.map(|dev_addr| format!("{:08X}", dev_addr)),
+                ],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let data_id = match data_id {
+            Some(data_id) => data_id,
+            None => {
+                info!(
+                    "Skipped duplicate uplink message (deviceID: \"{:}\", counter: {:})",
+                    msg.dev_id, msg.counter
+                );
+                return Ok(Outcome::Duplicate);
+            }
+        };
+
+        // Store the receptions and link them to the uplink:
+        for gtw in &msg.gateways {
+            self.statements.insert_gateway.execute([
+                &data_id as &dyn ToSql,
+                &gtw.gtw_id,
+                &gtw.time,
+                &gtw.timestamp,
+                &gtw.channel,
+                &gtw.rf_chain,
+                &gtw.rssi,
+                &gtw.snr,
+                &gtw.longitude,
+                &gtw.latitude,
+                &gtw.altitude,
+            ])?;
+        }
+
+        // Store the decoded values:
+        for measurement in decoded.measurements {
+            self.statements.insert_measurement.execute((
+                data_id,
+                measurement.channel,
+                measurement.kind,
+                measurement.value,
+                measurement.unit,
+            ))?;
+        }
+
+        Ok(Outcome::Inserted)
+    }
+}
@@ -0,0 +1,421 @@
+use crate::{env_var, Error};
+use aes::cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+use serde::Deserialize;
+use std::fs;
+
+// The message types of uplink data frames (in the upper 3 bits of the MHDR):
+const UNCONFIRMED_DATA_UP: u8 = 0b010;
+const CONFIRMED_DATA_UP: u8 = 0b100;
+
+// A LoRaWAN uplink data frame (PHYPayload) as it is sent over the air:
+// MHDR (1) | DevAddr (4) | FCtrl (1) | FCnt (2) | FOpts (0..15) | FPort (0..1) | FRMPayload (0..) | MIC (4)
+// All multi-byte fields are little endian.
+pub struct DataFrame {
+    bytes: Vec<u8>,
+    pub dev_addr: u32,
+    // Only the lower 16 bits of the frame counter are transmitted:
+    pub f_cnt: u16,
+    pub f_port: Option<u8>,
+    payload_start: usize,
+}
+
+impl DataFrame {
+    pub fn parse(bytes: Vec<u8>) -> Result<DataFrame, String> {
+        let m_type = bytes.first().ok_or("empty frame")? >> 5;
+
+        if m_type != UNCONFIRMED_DATA_UP && m_type != CONFIRMED_DATA_UP {
+            return Err(format!("frame of type {:} is no uplink data frame", m_type));
+        }
+
+        // MHDR, DevAddr, FCtrl, FCnt and MIC are mandatory:
+        if bytes.len() < 12 {
+            return Err(format!("frame is too short ({:} bytes)", bytes.len()));
+        }
+
+        let dev_addr = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        let f_opts_len = usize::from(bytes[5] & 0x0f);
+        let f_cnt = u16::from_le_bytes([bytes[6], bytes[7]]);
+
+        let mac_payload_end = bytes.len() - 4;
+        let f_port_index = 8 + f_opts_len;
+
+        let (f_port, payload_start) = if f_port_index < mac_payload_end {
+            (Some(bytes[f_port_index]), f_port_index + 1)
+        } else if f_port_index == mac_payload_end {
+            (None, mac_payload_end)
+        } else {
+            return Err(String::from("frame is too short for its FOpts"));
+        };
+
+        Ok(DataFrame {
+            bytes,
+            dev_addr,
+            f_cnt,
+            f_port,
+            payload_start,
+        })
+    }
+
+    // The encrypted FRMPayload:
+    pub fn frm_payload(&self) -> &[u8] {
+        &self.bytes[self.payload_start..self.bytes.len() - 4]
+    }
+
+    // The part of the frame the MIC is calculated over (MHDR and MACPayload):
+    fn signed_part(&self) -> &[u8] {
+        &self.bytes[..self.bytes.len() - 4]
+    }
+
+    fn mic(&self) -> &[u8] {
+        &self.bytes[self.bytes.len() - 4..]
+    }
+}
+
+// The upper 16 bits of the frame counter are not transmitted.
+// They are guessed from the last counter of the device, so that the result is as close to it as possible.
+pub fn restore_f_cnt(f_cnt: u16, last_f_cnt: Option<u32>) -> u32 {
+    let f_cnt = u32::from(f_cnt);
+
+    match last_f_cnt {
+        Some(last) => [-0x10000, 0, 0x10000]
+            .iter()
+            .map(|offset| i64::from(last & !0xffff) + i64::from(f_cnt) + offset)
+            .filter(|candidate| (0..=i64::from(u32::MAX)).contains(candidate))
+            .min_by_key(|candidate| (candidate - i64::from(last)).abs())
+            .map_or(f_cnt, |candidate| candidate as u32),
+        None => f_cnt,
+    }
+}
+
+type Key = [u8; 16];
+
+// The blocks that are used for MIC calculation and encryption (LoRaWAN 1.0.x, direction "up"):
+fn block(first: u8, dev_addr: u32, f_cnt: u32, last: u8) -> [u8; 16] {
+    let mut block = [0; 16];
+    block[0] = first;
+    block[6..10].copy_from_slice(&dev_addr.to_le_bytes());
+    block[10..14].copy_from_slice(&f_cnt.to_le_bytes());
+    block[15] = last;
+    block
+}
+
+fn verify_mic(nwk_s_key: &Key, frame: &DataFrame, f_cnt: u32) -> bool {
+    let signed_part = frame.signed_part();
+
+    let mut mac = <Cmac<Aes128> as Mac>::new(GenericArray::from_slice(nwk_s_key));
+    mac.update(&block(0x49, frame.dev_addr, f_cnt, signed_part.len() as u8));
+    mac.update(signed_part);
+
+    mac.finalize().into_bytes()[..4] == *frame.mic()
+}
+
+// Encryption and decryption are the same (XOR with a key stream):
+fn decrypt_payload(key: &Key, frame: &DataFrame, f_cnt: u32) -> Vec<u8> {
+    let cipher = Aes128::new(GenericArray::from_slice(key));
+
+    frame
+        .frm_payload()
+        .chunks(16)
+        .enumerate()
+        .flat_map(|(index, chunk)| {
+            let mut stream =
+                GenericArray::from(block(0x01, frame.dev_addr, f_cnt, index as u8 + 1));
+            cipher.encrypt_block(&mut stream);
+
+            chunk
+                .iter()
+                .zip(stream)
+                .map(|(byte, key_byte)| byte ^ key_byte)
+                .collect::<Vec<u8>>()
+        })
+        .collect()
+}
+
+// The file with the session keys of devices that send raw frames, written in TOML:
+//
+// [[device]]
+// dev_addr = "260B1234"
+// app_id = "my-app"
+// dev_id = "node-1"
+// dev_eui = "0004A30B001C0530"  # optional
+// app_s_key = "2B7E151628AED2A6ABF7158809CF4F3C"
+// nwk_s_key = "2B7E151628AED2A6ABF7158809CF4F3C"  # optional, the MIC is not verified without it
+//
+// Only LoRaWAN 1.0.x sessions are supported.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default)]
+    device: Vec<DeviceEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceEntry {
+    dev_addr: String,
+    app_id: String,
+    dev_id: String,
+    #[serde(default)]
+    dev_eui: String,
+    app_s_key: String,
+    nwk_s_key: Option<String>,
+}
+
+pub struct Session {
+    dev_addr: u32,
+    pub app_id: String,
+    pub dev_id: String,
+    pub dev_eui: String,
+    app_s_key: Key,
+    nwk_s_key: Option<Key>,
+}
+
+// A frame that has been attributed to a session and decrypted:
+pub struct OpenedFrame<'k> {
+    pub session: &'k Session,
+    pub f_cnt: u32,
+    pub payload: Vec<u8>,
+    // Nothing is known about the MIC if there is no NwkSKey:
+    pub mic_valid: Option<bool>,
+}
+
+// The session keys of all devices we know, read from a keys file (e.g. the one in "TTN2SQLITE_KEYS_FILE"):
+#[derive(Default)]
+pub struct Keys {
+    sessions: Vec<Session>,
+}
+
+fn parse_key(name: &str, hex: &str) -> Result<Key, Error> {
+    let invalid = || Error::Config(format!("{:} must be 32 hex digits", name));
+
+    if hex.len() != 32 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut key = [0; 16];
+
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * index..2 * index + 2], 16).map_err(|_| invalid())?;
+    }
+
+    Ok(key)
+}
+
+impl Keys {
+    // Without a keys file, no frame can be decrypted:
+    pub fn from_env() -> Result<Keys, Error> {
+        match env_var("TTN2SQLITE_KEYS_FILE") {
+            Some(path) => Keys::load(&path),
+            None => Ok(Keys::default()),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Keys, Error> {
+        let content = fs::read_to_string(path)?;
+        let file: KeysFile = toml::from_str(&content)
+            .map_err(|err| Error::Config(format!("invalid keys file \"{:}\": {:}", path, err)))?;
+
+        let sessions = file
+            .device
+            .into_iter()
+            .map(|entry| {
+                let dev_addr = u32::from_str_radix(&entry.dev_addr, 16)
+                    .ok()
+                    .filter(|_| entry.dev_addr.len() == 8)
+                    .ok_or_else(|| {
+                        Error::Config(format!(
+                            "dev_addr of device \"{:}\" must be 8 hex digits",
+                            entry.dev_id
+                        ))
+                    })?;
+
+                Ok(Session {
+                    dev_addr,
+                    app_s_key: parse_key("app_s_key", &entry.app_s_key)?,
+                    nwk_s_key: entry
+                        .nwk_s_key
+                        .as_deref()
+                        .map(|key| parse_key("nwk_s_key", key))
+                        .transpose()?,
+                    app_id: entry.app_id,
+                    dev_id: entry.dev_id,
+                    dev_eui: entry.dev_eui,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Keys { sessions })
+    }
+
+    // Attributes a frame to the session of its DevAddr, verifies its MIC and decrypts its payload.
+    // If multiple sessions share the DevAddr, the first one with a valid MIC wins.
+    // Nothing is returned if there is no session for the DevAddr.
+    //
+    // The frame counter is restored from the last counter of the device (as given by "last_f_cnt").
+    // If the MIC doesn't match that guess, the plain 16 bit counter is tried as well (because the device may have been reset).
+    pub fn open<F>(
+        &self,
+        frame: &DataFrame,
+        mut last_f_cnt: F,
+    ) -> Result<Option<OpenedFrame<'_>>, Error>
+    where
+        F: FnMut(&Session) -> Result<Option<u32>, Error>,
+    {
+        let mut opened = None;
+
+        for session in self
+            .sessions
+            .iter()
+            .filter(|session| session.dev_addr == frame.dev_addr)
+        {
+            let f_cnt = u32::from(frame.f_cnt);
+            let guess = restore_f_cnt(frame.f_cnt, last_f_cnt(session)?);
+
+            let (f_cnt, mic_valid) = match &session.nwk_s_key {
+                Some(nwk_s_key) => [guess, f_cnt]
+                    .iter()
+                    .copied()
+                    .find(|f_cnt| verify_mic(nwk_s_key, frame, *f_cnt))
+                    .map_or((guess, Some(false)), |f_cnt| (f_cnt, Some(true))),
+                None => (guess, None),
+            };
+
+            if opened.is_none() || mic_valid == Some(true) {
+                opened = Some((session, f_cnt, mic_valid));
+            }
+
+            if mic_valid == Some(true) {
+                break;
+            }
+        }
+
+        let (session, f_cnt, mic_valid) = match opened {
+            Some(opened) => opened,
+            None => return Ok(None),
+        };
+
+        // Port 0 carries MAC commands, which are encrypted with the NwkSKey:
+        let key = match frame.f_port {
+            Some(0) => session.nwk_s_key.as_ref().ok_or_else(|| {
+                Error::Decode(format!(
+                    "no nwk_s_key to decrypt MAC commands of DevAddr {:08X}",
+                    frame.dev_addr
+                ))
+            })?,
+            _ => &session.app_s_key,
+        };
+
+        Ok(Some(OpenedFrame {
+            session,
+            f_cnt,
+            payload: decrypt_payload(key, frame, f_cnt),
+            mic_valid,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn keys(dev_addr: u32, app_s_key: &str, nwk_s_key: Option<&str>) -> Keys {
+        Keys {
+            sessions: vec![Session {
+                dev_addr,
+                app_id: String::from("app"),
+                dev_id: String::from("node"),
+                dev_eui: String::new(),
+                app_s_key: parse_key("app_s_key", app_s_key).unwrap(),
+                nwk_s_key: nwk_s_key.map(|key| parse_key("nwk_s_key", key).unwrap()),
+            }],
+        }
+    }
+
+    const NWK_S_KEY: &str = "44024241ED4CE9A68C6A8BC055233FD3";
+    const APP_S_KEY: &str = "EC925802AE430CA77FD3DD73CB2CC588";
+
+    #[test]
+    fn opens_frame() {
+        let frame = DataFrame::parse(hex("40F17DBE4900020001954378762B11FF0D")).unwrap();
+
+        assert_eq!(frame.dev_addr, 0x49BE7DF1);
+        assert_eq!(frame.f_cnt, 2);
+        assert_eq!(frame.f_port, Some(1));
+
+        let keys = keys(0x49BE7DF1, APP_S_KEY, Some(NWK_S_KEY));
+        let opened = keys.open(&frame, |_| Ok(None)).unwrap().unwrap();
+
+        assert_eq!(opened.session.dev_id, "node");
+        assert_eq!(opened.f_cnt, 2);
+        assert_eq!(opened.payload, b"test");
+        assert_eq!(opened.mic_valid, Some(true));
+    }
+
+    #[test]
+    fn detects_invalid_mic() {
+        let frame = DataFrame::parse(hex("40F17DBE4900020001954378762B11FF0E")).unwrap();
+        let keys = keys(0x49BE7DF1, APP_S_KEY, Some(NWK_S_KEY));
+
+        assert_eq!(
+            keys.open(&frame, |_| Ok(None)).unwrap().unwrap().mic_valid,
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn restores_upper_frame_counter_bits() {
+        // No NwkSKey, so the guess is taken as it is:
+        let keys = keys(0x260B1234, APP_S_KEY, None);
+        let f_cnt = |f_cnt: u16, last: Option<u32>| {
+            let mut bytes = hex("4034120B2600000001AABBCCDDEE");
+            bytes[6..8].copy_from_slice(&f_cnt.to_le_bytes());
+
+            let frame = DataFrame::parse(bytes).unwrap();
+            keys.open(&frame, |_| Ok(last)).unwrap().unwrap().f_cnt
+        };
+
+        assert_eq!(f_cnt(0x0001, None), 0x0001);
+        assert_eq!(f_cnt(0x0001, Some(0x1FFFF)), 0x20001);
+        assert_eq!(f_cnt(0x0003, Some(0x20001)), 0x20003);
+        // A late frame from before the rollover:
+        assert_eq!(f_cnt(0xFFFE, Some(0x20001)), 0x1FFFE);
+        assert_eq!(f_cnt(0xFFFF, Some(0xFFFF_FFF0)), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn skips_frame_options() {
+        // FCtrl announces 3 bytes of FOpts before FPort 5 and 2 bytes of FRMPayload:
+        let frame = DataFrame::parse(hex("4034120B2603070001020305AABB11223344")).unwrap();
+
+        assert_eq!(frame.dev_addr, 0x260B1234);
+        assert_eq!(frame.f_cnt, 7);
+        assert_eq!(frame.f_port, Some(5));
+        assert_eq!(frame.frm_payload(), [0xAA, 0xBB]);
+
+        // Only FOpts, without FPort and FRMPayload:
+        let frame = DataFrame::parse(hex("4034120B2603070001020311223344")).unwrap();
+
+        assert_eq!(frame.f_port, None);
+        assert!(frame.frm_payload().is_empty());
+
+        // More FOpts than there are bytes:
+        assert!(DataFrame::parse(hex("4034120B26050700010211223344")).is_err());
+    }
+
+    #[test]
+    fn refuses_other_frames() {
+        // A join request:
+        assert!(DataFrame::parse(hex("00DC0000D07ED5B3701E6FEDF57CEEAF00C886030AF1")).is_err());
+        assert!(DataFrame::parse(hex("40F17DBE49")).is_err());
+        assert!(DataFrame::parse(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::decode::Measurement;
+
+// A Cayenne LPP data type.
+// Every value consists of "size" bytes (big endian) per dimension and is multiplied by "resolution".
+struct DataType {
+    id: u8,
+    names: &'static [&'static str],
+    size: usize,
+    signed: bool,
+    resolution: f64,
+}
+
+const fn data_type(
+    id: u8,
+    names: &'static [&'static str],
+    size: usize,
+    signed: bool,
+    resolution: f64,
+) -> DataType {
+    DataType {
+        id,
+        names,
+        size,
+        signed,
+        resolution,
+    }
+}
+
+// All data types we know, as defined by the Cayenne LPP specification (and its common extensions):
+const DATA_TYPES: &[DataType] = &[
+    data_type(0x00, &["digital_input"], 1, false, 1.0),
+    data_type(0x01, &["digital_output"], 1, false, 1.0),
+    data_type(0x02, &["analog_input"], 2, true, 0.01),
+    data_type(0x03, &["analog_output"], 2, true, 0.01),
+    data_type(0x64, &["generic"], 4, false, 1.0),
+    data_type(0x65, &["illuminance"], 2, false, 1.0),
+    data_type(0x66, &["presence"], 1, false, 1.0),
+    data_type(0x67, &["temperature"], 2, true, 0.1),
+    data_type(0x68, &["humidity"], 1, false, 0.5),
+    data_type(
+        0x71,
+        &["accelerometer_x", "accelerometer_y", "accelerometer_z"],
+        2,
+        true,
+        0.001,
+    ),
+    data_type(0x73, &["barometer"], 2, false, 0.1),
+    data_type(0x74, &["voltage"], 2, false, 0.01),
+    data_type(0x75, &["current"], 2, false, 0.001),
+    data_type(0x76, &["frequency"], 4, false, 1.0),
+    data_type(0x78, &["percentage"], 1, false, 1.0),
+    data_type(0x79, &["altitude"], 2, true, 1.0),
+    data_type(0x7d, &["concentration"], 2, false, 1.0),
+    data_type(0x80, &["power"], 2, false, 1.0),
+    data_type(0x82, &["distance"], 4, false, 0.001),
+    data_type(0x83, &["energy"], 4, false, 0.001),
+    data_type(0x84, &["direction"], 2, false, 1.0),
+    data_type(0x85, &["unix_time"], 4, false, 1.0),
+    data_type(
+        0x86,
+        &["gyrometer_x", "gyrometer_y", "gyrometer_z"],
+        2,
+        true,
+        0.01,
+    ),
+    data_type(0x87, &["colour_r", "colour_g", "colour_b"], 1, false, 1.0),
+    data_type(0x8e, &["switch"], 1, false, 1.0),
+];
+
+// GPS locations don't fit into the scheme above because their dimensions have different resolutions:
+const GPS_TYPE_ID: u8 = 0x88;
+const GPS_NAMES: [&str; 3] = ["gps_latitude", "gps_longitude", "gps_altitude"];
+const GPS_RESOLUTIONS: [f64; 3] = [0.0001, 0.0001, 0.01];
+const GPS_SIZE: usize = 3;
+
+// Reads a big endian integer of the given size (at most 4 bytes):
+fn read_int(bytes: &[u8], signed: bool) -> f64 {
+    let value = bytes
+        .iter()
+        .fold(0_u32, |acc, byte| (acc << 8) | u32::from(*byte));
+
+    if signed {
+        // Sign-extend by shifting the value to the top of an i32 and back:
+        let shift = 32 - 8 * bytes.len() as u32;
+        f64::from(((value << shift) as i32) >> shift)
+    } else {
+        f64::from(value)
+    }
+}
+
+// Decodes a Cayenne LPP payload into its measurements.
+// A payload is a sequence of (channel, type, value) frames.
+pub fn decode(payload: &[u8]) -> Result<Vec<Measurement>, String> {
+    let mut measurements = Vec::new();
+    let mut rest = payload;
+
+    while !rest.is_empty() {
+        let (channel, type_id) = match rest {
+            [channel, type_id, ..] => (*channel, *type_id),
+            _ => return Err(String::from("truncated frame header")),
+        };
+
+        rest = &rest[2..];
+
+        let (names, size, resolutions, signed): (&[&str], usize, [f64; 3], bool) = if type_id
+            == GPS_TYPE_ID
+        {
+            (&GPS_NAMES, GPS_SIZE, GPS_RESOLUTIONS, true)
+        } else {
+            let data_type = DATA_TYPES
+                .iter()
+                .find(|data_type| data_type.id == type_id)
+                .ok_or_else(|| format!("unknown type 0x{:02x} on channel {:}", type_id, channel))?;
+
+            (
+                data_type.names,
+                data_type.size,
+                [data_type.resolution; 3],
+                data_type.signed,
+            )
+        };
+
+        for (index, name) in names.iter().enumerate() {
+            if rest.len() < size {
+                return Err(format!("truncated value of type \"{:}\"", name));
+            }
+
+            measurements.push(Measurement {
+                channel: Some(u32::from(channel)),
+                kind: String::from(*name),
+                value: read_int(&rest[..size], signed) * resolutions[index],
+                unit: None,
+            });
+
+            rest = &rest[size..];
+        }
+    }
+
+    Ok(measurements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The channel, type and value of every measurement (the values rounded to the resolution of their types):
+    fn decoded(payload: &[u8]) -> Vec<(Option<u32>, String, f64)> {
+        decode(payload)
+            .unwrap()
+            .into_iter()
+            .map(|measurement| {
+                (
+                    measurement.channel,
+                    measurement.kind,
+                    (measurement.value * 10000.0).round() / 10000.0,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decodes_temperatures() {
+        assert_eq!(
+            decoded(&[0x03, 0x67, 0x01, 0x10, 0x05, 0x67, 0x00, 0xff]),
+            [
+                (Some(3), String::from("temperature"), 27.2),
+                (Some(5), String::from("temperature"), 25.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_negative_temperature() {
+        assert_eq!(
+            decoded(&[0x01, 0x67, 0xff, 0xd7]),
+            [(Some(1), String::from("temperature"), -4.1)]
+        );
+    }
+
+    #[test]
+    fn decodes_gps_location() {
+        assert_eq!(
+            decoded(&[0x01, 0x88, 0x06, 0x76, 0x5f, 0xf2, 0x96, 0x0a, 0x00, 0x03, 0xe8]),
+            [
+                (Some(1), String::from("gps_latitude"), 42.3519),
+                (Some(1), String::from("gps_longitude"), -87.9094),
+                (Some(1), String::from("gps_altitude"), 10.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_broken_payloads() {
+        assert!(decode(&[0x01]).is_err());
+        assert!(decode(&[0x01, 0x67, 0x01]).is_err());
+        assert!(decode(&[0x01, 0xff, 0x00]).is_err());
+    }
+}
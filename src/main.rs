@@ -1,178 +1,457 @@
-use base64::engine::{general_purpose::STANDARD as BASE64, Engine};
-use rusqlite::{Connection, Error as SQLiteError, Statement, ToSql};
-use serde::{de::Error as _, Deserialize, Deserializer};
-use serde_json::Error as JSONError;
-use std::io::{self, BufRead, Error as IOError};
-use std::{convert::From, env, fmt};
-
-// A universal error type for everything that can go wrong here:
-enum Error {
-    Io(IOError),
-    Json(JSONError),
-    SQLite(SQLiteError),
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        <Error as fmt::Debug>::fmt(self, f)
-    }
+use clap::builder::NonEmptyStringValueParser;
+use clap::{ArgGroup, Args, Parser, Subcommand, ValueEnum};
+use rusqlite::{Connection, OpenFlags};
+use std::io::{self, BufRead, BufWriter};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use ttn2sqlite::batch::{Batch, BatchConfig};
+use ttn2sqlite::decode::Decoders;
+use ttn2sqlite::export::{self, ExportFormat};
+use ttn2sqlite::formats::Formats;
+use ttn2sqlite::forwarder::{self, ForwarderConfig};
+use ttn2sqlite::lorawan::Keys;
+use ttn2sqlite::mqtt::{self, MqttConfig};
+use ttn2sqlite::schema::SchemaConfig;
+use ttn2sqlite::webhook::{self, WebhookConfig};
+use ttn2sqlite::{
+    info, parse_time, prune, reprocess, schema, stats, Error, Ingestor, Input, Outcome, Verbosity,
+};
+
+// How many received lines may queue up before the input sources are blocked:
+const INPUT_QUEUE_SIZE: usize = 1024;
+
+// The command line.
+// Without a command, messages are ingested (so "ttn2sqlite my_db.sqlite" works like it always did).
+// Everything that can't be given here (e.g. the MQTT broker or the decoders) is configured by "TTN2SQLITE_*" environment variables.
+// Unlike those, the variables that stand in for arguments (e.g. "TTN2SQLITE_DB") must not be empty.
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Stores LoRaWAN uplinks (from TTN, ChirpStack, Helium or packet forwarders) in an SQLite DB",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    ingest: IngestArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Store the messages of an input source (the default)")]
+    Ingest(IngestArgs),
+    #[command(about = "Run the rejected messages through the current parser and decoders again")]
+    Reprocess(ReprocessArgs),
+    #[command(about = "Bring the schema of the DB up to date")]
+    Migrate(MigrateArgs),
+    #[command(about = "Write a table to stdout")]
+    Export(ExportArgs),
+    #[command(about = "Print an overview of the DB")]
+    Stats(CommonArgs),
+    #[command(about = "Delete old uplinks")]
+    Prune(PruneArgs),
 }
 
-impl fmt::Debug for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl Command {
+    fn common(&self) -> &CommonArgs {
         match self {
-            Error::Io(err) => write!(f, "IO error ({:})", err),
-            Error::Json(err) => write!(f, "JSON error ({:})", err),
-            Error::SQLite(err) => write!(f, "SQLite error ({:})", err),
+            Command::Ingest(args) => &args.common,
+            Command::Reprocess(args) => &args.common,
+            Command::Migrate(args) => &args.common,
+            Command::Stats(args) => args,
+            Command::Export(args) => &args.common,
+            Command::Prune(args) => &args.common,
         }
     }
 }
 
-impl From<IOError> for Error {
-    fn from(err: IOError) -> Self {
-        Error::Io(err)
-    }
+// What every command takes:
+#[derive(Args)]
+struct CommonArgs {
+    #[arg(
+        env = "TTN2SQLITE_DB",
+        default_value = "ttn_db.sqlite",
+        value_parser = NonEmptyStringValueParser::new(),
+        help = "The path of the SQLite DB"
+    )]
+    db: String,
+    #[arg(
+        short,
+        long,
+        help = "Print everything, including the batches and dropped frames"
+    )]
+    verbose: bool,
+    #[arg(
+        short,
+        long,
+        conflicts_with = "verbose",
+        help = "Print only errors and warnings"
+    )]
+    quiet: bool,
 }
 
-impl From<JSONError> for Error {
-    fn from(err: JSONError) -> Self {
-        Error::Json(err)
+impl CommonArgs {
+    fn verbosity(&self) -> Verbosity {
+        if self.quiet {
+            Verbosity::Quiet
+        } else if self.verbose {
+            Verbosity::Verbose
+        } else {
+            Verbosity::Normal
+        }
     }
 }
 
-impl From<SQLiteError> for Error {
-    fn from(err: SQLiteError) -> Self {
-        Error::SQLite(err)
-    }
+// Where messages come from.
+// "auto" picks the first one that is configured by the environment (in this order), stdin otherwise.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Source {
+    Auto,
+    Mqtt,
+    Webhook,
+    Forwarder,
+    Stdin,
 }
 
-// The data format returned from TTN:
-#[derive(Deserialize)]
-struct UplinkMessage<'l> {
-    app_id: &'l str,
-    dev_id: &'l str,
-    hardware_serial: &'l str,
-    port: u32,
-    counter: u32,
-    metadata: UplinkMetadata<'l>,
+#[derive(Args)]
+struct IngestArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[arg(long, value_enum, default_value_t = Source::Auto, help = "Where messages come from")]
+    source: Source,
+    #[arg(
+        long,
+        env = "TTN2SQLITE_FORMAT",
+        value_parser = NonEmptyStringValueParser::new(),
+        help = "The input format, detected for every message if \"auto\""
+    )]
+    format: Option<String>,
+    #[arg(
+        long,
+        env = "TTN2SQLITE_BATCH_SIZE",
+        default_value_t = BatchConfig::DEFAULT_SIZE,
+        help = "The number of messages that are committed together"
+    )]
+    batch_size: usize,
+    #[arg(
+        long,
+        env = "TTN2SQLITE_BATCH_INTERVAL_MS",
+        default_value_t = BatchConfig::DEFAULT_INTERVAL_MS,
+        help = "How long a batch may stay open (in milliseconds)"
+    )]
+    batch_interval_ms: u64,
+}
+
+#[derive(Args)]
+struct ReprocessArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[arg(
+        long,
+        env = "TTN2SQLITE_FORMAT",
+        value_parser = NonEmptyStringValueParser::new(),
+        help = "The input format, detected for every message if \"auto\""
+    )]
+    format: Option<String>,
+}
 
-    // The payload is a blob of up to Payload::MAX_PAYLOAD_SIZE bytes.
-    // It is stored as Base64 string (JSON field name is "payload_raw").
-    // The function "deserialize_payload" (defined below) manages its deserialization.
-    #[serde(rename = "payload_raw", deserialize_with = "deserialize_payload")]
-    payload: Payload,
+#[derive(Args)]
+struct MigrateArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[arg(
+        long,
+        help = "Delete the uplinks that are duplicates according to a new dedup key (keeping the first one)"
+    )]
+    remove_duplicates: bool,
 }
 
-#[derive(Deserialize)]
-struct UplinkMetadata<'l> {
-    time: &'l str,
-    longitude: f64,
-    latitude: f64,
-    altitude: f64,
+#[derive(Args)]
+struct ExportArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[arg(
+        long,
+        default_value = "uplinks",
+        help = "The table (or view) to export, e.g. data, gateways, measurements or rejected"
+    )]
+    table: String,
+    #[arg(long, help = "Write CSV instead of one JSON object per line")]
+    csv: bool,
 }
 
-struct Payload {
-    bytes: [u8; Payload::MAX_PAYLOAD_SIZE],
-    size: usize,
+#[derive(Args)]
+#[command(group(ArgGroup::new("cutoff").required(true).args(["before", "older_than"])))]
+struct PruneArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[arg(long, help = "Delete the uplinks before this time (RFC 3339)")]
+    before: Option<String>,
+    #[arg(
+        long,
+        value_parser = prune::parse_age,
+        help = "Delete the uplinks older than this (e.g. 90d, 12h, 30m or 45s)"
+    )]
+    older_than: Option<Duration>,
+    #[arg(long, help = "Delete the rejected messages before the same time, too")]
+    rejected: bool,
+    #[arg(long, help = "Give the freed space back to the file system afterwards")]
+    vacuum: bool,
 }
 
-impl Payload {
-    // The maximum payload size in bytes, as defined by TTN:
-    const MAX_PAYLOAD_SIZE: usize = 512;
+fn main() -> Result<(), Error> {
+    let cli = Cli::parse();
+    let command = cli.command.unwrap_or(Command::Ingest(cli.ingest));
+    ttn2sqlite::set_verbosity(command.common().verbosity());
+
+    match command {
+        Command::Ingest(args) => ingest(args),
+        Command::Reprocess(args) => {
+            let db_connection = Connection::open(&args.common.db)?;
+            schema::setup(&db_connection, &SchemaConfig::from_env()?)?;
+
+            let mut ingestor = Ingestor::new(
+                &db_connection,
+                formats(args.format.as_deref())?,
+                Decoders::from_env()?,
+                Keys::from_env()?,
+            )?;
 
-    fn empty() -> Payload {
-        Payload {
-            bytes: [0; Payload::MAX_PAYLOAD_SIZE],
-            size: 0,
+            reprocess::run(&mut ingestor)
         }
-    }
+        Command::Migrate(args) => schema::setup(
+            &Connection::open(&args.common.db)?,
+            &SchemaConfig::from_env()?.removing_duplicates(args.remove_duplicates),
+        ),
+        Command::Export(args) => {
+            // Exports are read-only, so they don't migrate (or create) the DB:
+            let db_connection =
+                Connection::open_with_flags(&args.common.db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            let format = if args.csv {
+                ExportFormat::Csv
+            } else {
+                ExportFormat::JsonLines
+            };
+
+            export::run(
+                &db_connection,
+                &args.table,
+                format,
+                &mut BufWriter::new(io::stdout().lock()),
+            )
+        }
+        Command::Stats(args) => {
+            let db_connection =
+                Connection::open_with_flags(&args.db, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            schema::check(&db_connection)?;
+
+            stats::run(&db_connection)
+        }
+        Command::Prune(args) => {
+            let db_connection = Connection::open(&args.common.db)?;
+            schema::check(&db_connection)?;
+
+            let before_ns = match (args.before, args.older_than) {
+                (Some(before), _) => parse_time(&before)?,
+                (None, Some(age)) => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
 
-    fn as_slice(&self) -> &[u8] {
-        &self.bytes[0..self.size]
+                    now.saturating_sub(age).as_nanos() as i64
+                }
+                (None, None) => unreachable!("clap requires one of them"),
+            };
+
+            prune::run(&db_connection, before_ns, args.rejected)?;
+
+            if args.vacuum {
+                db_connection.execute_batch("VACUUM")?;
+            }
+
+            Ok(())
+        }
     }
 }
 
-// This function is responsible for deserializing the "raw_payload" JSON string into the "payload" field of our "UplinkMessage" struct.
-fn deserialize_payload<'de, D>(deserializer: D) -> Result<Payload, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    // Extract the JSON value as string slice:
-    let input = <&str as Deserialize>::deserialize(deserializer)?;
-
-    // Decode the Base64 string into our array:
-    let mut payload = Payload::empty();
-    payload.size = BASE64
-        .decode_slice(input, &mut payload.bytes)
-        .map_err(|err| D::Error::custom(err.to_string()))?;
-
-    Ok(payload)
-}
-
-// This function deserializes a message from JSON into a struct.
-// Then it tries to insert all the data into our DB.
-fn process_line(line: &str, db_stmt: &mut Statement) -> Result<(), Error> {
-    // Try to deserialize the message:
-    let msg: UplinkMessage = serde_json::from_str(&line)?;
-
-    // Print some info about it:
-    println!("Received uplink message (appID: \"{:}\", deviceID: \"{:}\", time: \"{:}\", payload: {:} bytes)", msg.app_id, msg.dev_id, msg.metadata.time, msg.payload.size);
-
-    // Store it into our database:
-    db_stmt.execute(&[
-        &msg.app_id as &dyn ToSql,
-        &msg.dev_id,
-        &msg.hardware_serial,
-        &msg.port,
-        &msg.counter,
-        &msg.metadata.time,
-        &msg.metadata.longitude,
-        &msg.metadata.latitude,
-        &msg.metadata.altitude,
-        &msg.payload.as_slice(),
-    ])?;
+// The built-in formats, one of them forced if a name is given:
+fn formats(name: Option<&str>) -> Result<Formats, Error> {
+    let mut formats = Formats::default();
 
-    Ok(())
+    if let Some(name) = name {
+        formats.force(name)?;
+    }
+
+    Ok(formats)
 }
 
-fn main() -> Result<(), Error> {
-    // Get the path to the DB as CLI argument.
-    // If there is none, we use a default.
-    let db_path = env::args().nth(1).unwrap_or(String::from("ttn_db.sqlite"));
+// The configured input sources:
+enum InputSource {
+    Mqtt(MqttConfig),
+    Webhook(WebhookConfig),
+    Forwarder(ForwarderConfig),
+    Stdin,
+}
+
+impl InputSource {
+    fn select(source: Source) -> Result<InputSource, Error> {
+        let missing = |name: &str| {
+            Error::Config(format!(
+                "this source needs {:} to be set in the environment",
+                name
+            ))
+        };
+
+        if matches!(source, Source::Auto | Source::Mqtt) {
+            if let Some(config) = MqttConfig::from_env()? {
+                return Ok(InputSource::Mqtt(config));
+            } else if source == Source::Mqtt {
+                return Err(missing("TTN2SQLITE_MQTT_HOST"));
+            }
+        }
 
+        if matches!(source, Source::Auto | Source::Webhook) {
+            if let Some(config) = WebhookConfig::from_env()? {
+                return Ok(InputSource::Webhook(config));
+            } else if source == Source::Webhook {
+                return Err(missing("TTN2SQLITE_WEBHOOK_ADDR"));
+            }
+        }
+
+        if matches!(source, Source::Auto | Source::Forwarder) {
+            if let Some(config) = ForwarderConfig::from_env()? {
+                return Ok(InputSource::Forwarder(config));
+            } else if source == Source::Forwarder {
+                return Err(missing("TTN2SQLITE_FORWARDER_ADDR"));
+            }
+        }
+
+        Ok(InputSource::Stdin)
+    }
+
+    fn run(&self, sender: &SyncSender<Input>) -> Result<(), Error> {
+        match self {
+            InputSource::Mqtt(config) => mqtt::subscribe(config, sender),
+            InputSource::Webhook(config) => webhook::serve(config, sender),
+            InputSource::Forwarder(config) => forwarder::listen(config, sender),
+            InputSource::Stdin => read_stdin(sender),
+        }
+    }
+}
+
+fn ingest(args: IngestArgs) -> Result<(), Error> {
     // Open the output database.
     // It may already exist.
-    let db_connection = Connection::open(&db_path)?;
-
-    // Create the data table if it is not yet there:
-    db_connection.execute(
-        "CREATE TABLE IF NOT EXISTS data (
-        	app_id TEXT NOT NULL, dev_id TEXT NOT NULL, hardware_serial TEXT NOT NULL,
-        	port INTEGER NOT NULL, counter INTEGER NOT NULL, time TEXT NOT NULL,
-        	lon REAL NOT NULL, lat REAL NOT NULL, alt REAL NOT NULL, payload BLOB NOT NULL
-        )",
-        [],
+    let db_connection = Connection::open(&args.common.db)?;
+    schema::setup(&db_connection, &SchemaConfig::from_env()?)?;
+
+    // Prepare the statements for insertion (along with formats, decoders and keys):
+    let mut ingestor = Ingestor::new(
+        &db_connection,
+        formats(args.format.as_deref())?,
+        Decoders::from_env()?,
+        Keys::from_env()?,
     )?;
 
-    // Prepare a statement for insertion:
-    let mut db_stmt = db_connection.prepare(
-        "INSERT INTO data
-        	(app_id, dev_id, hardware_serial, port, counter, time, lon, lat, alt, payload)
-    		VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    let batch_config = BatchConfig::new(
+        args.batch_size,
+        Duration::from_millis(args.batch_interval_ms),
     )?;
+    let mut batch = Batch::new(&db_connection, batch_config)?;
+
+    // Start the input source:
+    let (sender, receiver) = mpsc::sync_channel(INPUT_QUEUE_SIZE);
+    let source = InputSource::select(args.source)?;
+
+    {
+        let sender = sender.clone();
+
+        thread::spawn(move || {
+            let result = source.run(&sender);
+            _ = sender.send(Input::End(result));
+        });
+    }
+
+    // Flush the current batch before we are terminated:
+    ctrlc::set_handler(move || _ = sender.send(Input::End(Ok(()))))
+        .map_err(|err| Error::Config(format!("cannot install signal handler: {:}", err)))?;
+
+    let mut duplicates = 0;
+
+    let result = loop {
+        // Wait for the next line, but not longer than until the open transaction is due:
+        let input = match batch.deadline() {
+            Some(deadline) => {
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => receiver.recv().map_err(RecvTimeoutError::from),
+        };
+
+        match input {
+            Ok(Input::Line(line, completion)) => {
+                // Print errors to the terminal (but don't kill the whole program).
+                batch.process(
+                    &line,
+                    || match ingestor.ingest_line(&line) {
+                        Ok(outcome) => {
+                            if outcome == Outcome::Duplicate {
+                                duplicates += 1;
+                            }
+
+                            Ok(())
+                        }
+                        Err(err) => {
+                            println!("Error while processing message:\n{:}", err);
+                            Err(err)
+                        }
+                    },
+                    completion,
+                )?;
+            }
+            Ok(Input::Invalid(err, completion)) => {
+                let bytes = err.as_bytes();
+                let err = Error::from(err.utf8_error());
+                println!("Error while processing message:\n{:}", err);
+                batch.reject(bytes, err, completion)?;
+            }
+            Ok(Input::End(result)) => break result,
+            Err(RecvTimeoutError::Timeout) => batch.commit()?,
+            Err(RecvTimeoutError::Disconnected) => break Ok(()),
+        }
+    };
+
+    batch.commit()?;
+    info!("Skipped {:} duplicate uplink messages", duplicates);
+
+    result
+}
+
+// Reads lines from stdin.
+// Each line represents a JSON-encoded uplink message.
+fn read_stdin(sender: &SyncSender<Input>) -> Result<(), Error> {
+    let mut stdin = io::stdin().lock();
+
+    loop {
+        // Lines are read as bytes, so those that aren't valid UTF-8 still end up in "rejected":
+        let mut line = Vec::new();
+
+        if stdin.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        if line.ends_with(b"\n") {
+            line.pop();
+
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
 
-    // Read lines from stdin.
-    // Each line represents a JSON-encoded uplink message.
-    let stdin = io::stdin();
-
-    for line in stdin.lock().lines() {
-        // Try to read a new line from stdin and to parse it.
-        // Print errors to the terminal (but don't kill the whole program).
-        if let Err(err) = line
-            .map_err(|err| err.into())
-            .and_then(|l| process_line(&l, &mut db_stmt))
-        {
-            println!("Error while processing message:\n{:}", err);
+        // The receiver is gone, so we are shutting down:
+        if sender.send(Input::from_bytes(line, None)).is_err() {
+            break;
         }
     }
 
@@ -0,0 +1,138 @@
+use crate::{env_flag, env_parse, env_var, info, Delivery, Error, Input};
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS, Transport};
+use std::{fs, sync::mpsc::SyncSender, thread, time::Duration};
+
+// The configuration of the MQTT input mode.
+// It is read from the environment and only present if "TTN2SQLITE_MQTT_HOST" is set.
+pub struct MqttConfig {
+    host: String,
+    port: u16,
+    tls: bool,
+    ca_file: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    topic: String,
+    client_id: String,
+}
+
+impl MqttConfig {
+    // The topic filter that matches all uplinks of all applications in TTN v2:
+    const DEFAULT_TOPIC: &'static str = "+/devices/+/up";
+
+    // The broker identifies our persistent session by this ID:
+    const DEFAULT_CLIENT_ID: &'static str = "ttn2sqlite";
+
+    pub fn from_env() -> Result<Option<MqttConfig>, Error> {
+        let host = match env_var("TTN2SQLITE_MQTT_HOST") {
+            Some(host) => host,
+            None => return Ok(None),
+        };
+
+        // A CA file implies TLS:
+        let ca_file = env_var("TTN2SQLITE_MQTT_CA_FILE");
+        let tls = env_flag("TTN2SQLITE_MQTT_TLS") || ca_file.is_some();
+        let port = env_parse("TTN2SQLITE_MQTT_PORT")?.unwrap_or(if tls { 8883 } else { 1883 });
+
+        Ok(Some(MqttConfig {
+            host,
+            port,
+            tls,
+            ca_file,
+            username: env_var("TTN2SQLITE_MQTT_USERNAME"),
+            password: env_var("TTN2SQLITE_MQTT_PASSWORD"),
+            topic: env_var("TTN2SQLITE_MQTT_TOPIC")
+                .unwrap_or_else(|| String::from(Self::DEFAULT_TOPIC)),
+            client_id: env_var("TTN2SQLITE_MQTT_CLIENT_ID")
+                .unwrap_or_else(|| String::from(Self::DEFAULT_CLIENT_ID)),
+        }))
+    }
+}
+
+// The maximum size of an incoming MQTT packet in bytes.
+// TTN v3 uplinks with many gateways easily exceed the default of 10 KiB.
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+// How long we wait before reconnecting after the connection to the broker has failed:
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// Connects to the broker and sends the payload of every received message as input line.
+// Messages are received with QoS 1 in a persistent session and only acknowledged after they have been handled.
+// That way, the broker keeps everything that arrives while we are not running.
+pub fn subscribe(config: &MqttConfig, sender: &SyncSender<Input>) -> Result<(), Error> {
+    let mut options =
+        MqttOptions::new(config.client_id.as_str(), config.host.as_str(), config.port);
+    options
+        .set_clean_session(false)
+        .set_manual_acks(true)
+        .set_keep_alive(Duration::from_secs(30))
+        .set_max_packet_size(MAX_PACKET_SIZE, MAX_PACKET_SIZE);
+
+    if let Some(username) = &config.username {
+        options.set_credentials(username.as_str(), config.password.as_deref().unwrap_or(""));
+    }
+
+    if config.tls {
+        options.set_transport(match &config.ca_file {
+            Some(ca_file) => Transport::tls(fs::read(ca_file)?, None, None),
+            None => Transport::tls_with_default_config(),
+        });
+    }
+
+    let (client, mut connection) = Client::new(options, 16);
+
+    for notification in connection.iter() {
+        match notification {
+            // We (re-)subscribe on every connection, even if the broker has kept our session.
+            // The session may hold the subscription to another topic (e.g. before the configured one has been changed).
+            // Subscribing to the same topic again is harmless, the broker only replaces it.
+            Ok(Event::Incoming(Packet::ConnAck(ack))) => {
+                info!(
+                    "Connected to MQTT broker {:}:{:} (session present: {:})",
+                    config.host, config.port, ack.session_present
+                );
+
+                client.try_subscribe(config.topic.as_str(), QoS::AtLeastOnce)?;
+            }
+
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let bytes = publish.payload.to_vec();
+                let client = client.clone();
+
+                // Messages that have been rejected are acknowledged as well (they are kept in "rejected").
+                // Redelivering them won't change their content.
+                // If the transaction has failed, nothing is acknowledged, so the broker delivers it again once we are back.
+                let completion = Box::new(move |delivery: Delivery| {
+                    if let Delivery::Failed(_) = delivery {
+                        return;
+                    }
+
+                    if let Err(err) = client.ack(&publish) {
+                        println!("Error while acknowledging MQTT message: {:}", err);
+                    }
+                });
+
+                // The receiver is gone, so we are shutting down:
+                if sender
+                    .send(Input::from_bytes(bytes, Some(completion)))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+
+            Ok(_) => (),
+
+            // The connection is re-established on the next iteration.
+            Err(err) => {
+                println!(
+                    "MQTT connection error ({:}), reconnecting in {:} s",
+                    err,
+                    RECONNECT_DELAY.as_secs()
+                );
+                thread::sleep(RECONNECT_DELAY);
+            }
+        }
+    }
+
+    Ok(())
+}
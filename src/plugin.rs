@@ -0,0 +1,496 @@
+use crate::{Error, Uplink};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fs;
+use wasmi::{
+    Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+};
+
+// The file that configures the WebAssembly decoder plugins and their routing, written in TOML:
+//
+// fuel = 10000000        # optional, the number of instructions a single run may execute
+// max_memory = 16777216  # optional, the number of bytes the linear memory of a run may grow to
+//
+// [[plugin]]
+// path = "decoders/air-quality.wasm"
+// app_id = "my-app"  # optional, matches all applications if missing
+// dev_id = "node-1"  # optional, matches all devices if missing
+// port = 2           # optional, matches all ports if missing
+//
+// The first plugin that matches an uplink decodes it.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PluginFile {
+    #[serde(default = "PluginFile::default_fuel")]
+    fuel: u64,
+    #[serde(default = "PluginFile::default_max_memory")]
+    max_memory: usize,
+    #[serde(default)]
+    plugin: Vec<Route>,
+}
+
+impl PluginFile {
+    fn default_fuel() -> u64 {
+        10_000_000
+    }
+
+    fn default_max_memory() -> usize {
+        16 * 1024 * 1024
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Route {
+    path: String,
+    app_id: Option<String>,
+    dev_id: Option<String>,
+    port: Option<u32>,
+}
+
+// A compiled plugin along with the uplinks it is responsible for.
+//
+// A plugin is a module without imports that exports:
+// - "memory", its linear memory
+// - "alloc(len: i32) -> i32", which reserves "len" bytes for the payload and returns their address
+// - "decode(ptr: i32, len: i32, port: i32) -> i64", which returns the address (upper 32 bits) and length (lower 32 bits) of a JSON result
+//
+// The result is an object of fields (like the ones of scripts) or "null" if the plugin doesn't handle the payload.
+// Every run gets a fresh instance, so nothing leaks from one uplink to the next.
+struct Plugin {
+    route: Route,
+    module: Module,
+}
+
+impl Plugin {
+    fn matches(&self, uplink: &Uplink) -> bool {
+        self.route.port.is_none_or(|port| port == uplink.port)
+            && self
+                .route
+                .app_id
+                .as_deref()
+                .is_none_or(|id| id == uplink.app_id)
+            && self
+                .route
+                .dev_id
+                .as_deref()
+                .is_none_or(|id| id == uplink.dev_id)
+    }
+}
+
+pub struct Plugins {
+    engine: Engine,
+    fuel: u64,
+    max_memory: usize,
+    plugins: Vec<Plugin>,
+}
+
+impl Plugins {
+    // Loads the configuration and compiles all plugins in it.
+    // Every plugin is instantiated once, so missing exports are noticed at startup.
+    pub fn load(path: &str) -> Result<Plugins, Error> {
+        let content = fs::read_to_string(path)?;
+        let file: PluginFile = toml::from_str(&content)
+            .map_err(|err| Error::Config(format!("invalid plugin file \"{:}\": {:}", path, err)))?;
+
+        let mut config = Config::default();
+        config.consume_fuel(true);
+
+        let mut plugins = Plugins {
+            engine: Engine::new(&config),
+            fuel: file.fuel,
+            max_memory: file.max_memory,
+            plugins: Vec::new(),
+        };
+
+        for route in file.plugin {
+            let plugin_error = |err: String| {
+                Error::Config(format!("invalid plugin \"{:}\": {:}", route.path, err))
+            };
+
+            let module = Module::new(&plugins.engine, fs::read(&route.path)?.as_slice())
+                .map_err(|err| plugin_error(err.to_string()))?;
+
+            plugins.instantiate(&module).map_err(plugin_error)?;
+
+            plugins.plugins.push(Plugin { route, module });
+        }
+
+        Ok(plugins)
+    }
+
+    // Creates a fresh instance of a plugin with its own limits:
+    fn instantiate(&self, module: &Module) -> Result<(Store<StoreLimits>, Instance), String> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.max_memory)
+            .instances(1)
+            .build();
+
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits| limits);
+        store.add_fuel(self.fuel).map_err(|err| err.to_string())?;
+
+        let instance = Linker::new(&self.engine)
+            .instantiate(&mut store, module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(|err| err.to_string())?;
+
+        for (name, exported) in [
+            ("memory", instance.get_memory(&store, "memory").is_some()),
+            (
+                "alloc",
+                instance.get_typed_func::<i32, i32>(&store, "alloc").is_ok(),
+            ),
+            (
+                "decode",
+                instance
+                    .get_typed_func::<(i32, i32, i32), i64>(&store, "decode")
+                    .is_ok(),
+            ),
+        ] {
+            if !exported {
+                return Err(format!(
+                    "\"{:}\" is not exported (with the expected type)",
+                    name
+                ));
+            }
+        }
+
+        Ok((store, instance))
+    }
+
+    // Runs the first plugin that matches an uplink and returns its fields.
+    // Nothing is returned if there is none or if it doesn't handle the payload.
+    pub fn decode(&self, uplink: &Uplink) -> Result<Option<Map<String, Value>>, String> {
+        let plugin = match self.plugins.iter().find(|plugin| plugin.matches(uplink)) {
+            Some(plugin) => plugin,
+            None => return Ok(None),
+        };
+
+        let (mut store, instance) = self.instantiate(&plugin.module)?;
+        let memory: Memory = instance.get_memory(&store, "memory").ok_or("no memory")?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(|err| err.to_string())?;
+        let decode = instance
+            .get_typed_func::<(i32, i32, i32), i64>(&store, "decode")
+            .map_err(|err| err.to_string())?;
+
+        // Copy the payload into the plugin:
+        let payload = uplink.payload.as_slice();
+        let len = payload.len() as i32;
+        let ptr = alloc
+            .call(&mut store, len)
+            .map_err(|err| format!("{:} (in \"{:}\")", err, plugin.route.path))?;
+
+        memory
+            .write(&mut store, ptr as u32 as usize, payload)
+            .map_err(|err| err.to_string())?;
+
+        // Run it and copy the result back out:
+        let result = decode
+            .call(&mut store, (ptr, len, uplink.port as i32))
+            .map_err(|err| format!("{:} (in \"{:}\")", err, plugin.route.path))?;
+
+        let result_ptr = (result as u64 >> 32) as usize;
+        let result_len = (result as u64 & 0xffff_ffff) as usize;
+
+        let json = memory
+            .data(&store)
+            .get(result_ptr..result_ptr + result_len)
+            .ok_or("result is out of bounds")?;
+
+        match serde_json::from_slice(json).map_err(|err| err.to_string())? {
+            Value::Null => Ok(None),
+            Value::Object(fields) => Ok(Some(fields)),
+            _ => Err(String::from("result must be an object or null")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::formats::Formats;
+    use std::env;
+
+    // Encodes an unsigned LEB128 number:
+    fn uleb(mut n: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+
+            if n == 0 {
+                out.push(byte);
+                return out;
+            }
+
+            out.push(byte | 0x80);
+        }
+    }
+
+    // Encodes a signed LEB128 number:
+    fn sleb(mut n: i64) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+
+            if (n == 0 && byte & 0x40 == 0) || (n == -1 && byte & 0x40 != 0) {
+                out.push(byte);
+                return out;
+            }
+
+            out.push(byte | 0x80);
+        }
+    }
+
+    fn vector(items: &[Vec<u8>]) -> Vec<u8> {
+        [uleb(items.len() as u64), items.concat()].concat()
+    }
+
+    fn section(id: u8, content: Vec<u8>) -> Vec<u8> {
+        [vec![id], uleb(content.len() as u64), content].concat()
+    }
+
+    // Assembles a module with a single page of memory that holds "data" at address 1024.
+    // "alloc" always returns 0, "decode" runs the given instructions. Only the given exports are exported.
+    fn module(decode: &[u8], data: &[u8], exports: &[&str]) -> Vec<u8> {
+        // (i32) -> i32 and (i32, i32, i32) -> i64:
+        let types = vector(&[
+            vec![0x60, 1, 0x7f, 1, 0x7f],
+            vec![0x60, 3, 0x7f, 0x7f, 0x7f, 1, 0x7e],
+        ]);
+        let functions = vector(&[vec![0], vec![1]]);
+        let memory = vector(&[vec![0x00, 1]]);
+
+        let exports: Vec<Vec<u8>> = exports
+            .iter()
+            .map(|&export| {
+                let (kind, index) = match export {
+                    "memory" => (0x02, 0),
+                    "alloc" => (0x00, 0),
+                    _ => (0x00, 1),
+                };
+
+                [
+                    uleb(export.len() as u64),
+                    export.as_bytes().to_vec(),
+                    vec![kind, index],
+                ]
+                .concat()
+            })
+            .collect();
+
+        // No locals, the instructions and "end":
+        let alloc = vec![0x00, 0x41, 0x00, 0x0b];
+        let decode = [&[0x00], decode, &[0x0b]].concat();
+        let code = vector(&[
+            [uleb(alloc.len() as u64), alloc].concat(),
+            [uleb(decode.len() as u64), decode].concat(),
+        ]);
+
+        let data = vector(&[[
+            vec![0x00, 0x41],
+            sleb(1024),
+            vec![0x0b],
+            uleb(data.len() as u64),
+            data.to_vec(),
+        ]
+        .concat()]);
+
+        [
+            b"\0asm\x01\0\0\0".to_vec(),
+            section(1, types),
+            section(3, functions),
+            section(5, memory),
+            section(7, vector(&exports)),
+            section(10, code),
+            section(11, data),
+        ]
+        .concat()
+    }
+
+    // The instructions that return a result at the given address with the given length:
+    fn returning(ptr: i64, len: usize) -> Vec<u8> {
+        [vec![0x42], sleb(ptr << 32 | len as i64)].concat()
+    }
+
+    // A plugin whose "decode" always returns "json":
+    fn constant(json: &str) -> Vec<u8> {
+        module(
+            &returning(1024, json.len()),
+            json.as_bytes(),
+            &["memory", "alloc", "decode"],
+        )
+    }
+
+    // Writes the modules and a plugin file with the given header that routes to them.
+    // Every test gets a directory of its own (they run in parallel).
+    fn load(name: &str, header: &str, plugins: &[(&str, Vec<u8>)]) -> Result<Plugins, Error> {
+        let dir = env::temp_dir().join(format!(
+            "ttn2sqlite-plugin-{:}-{:}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut file = format!("{:}\n", header);
+
+        for (index, (route, module)) in plugins.iter().enumerate() {
+            let path = dir.join(format!("{:}.wasm", index));
+            fs::write(&path, module).unwrap();
+            file += &format!(
+                "[[plugin]]\npath = {:?}\n{:}\n",
+                path.to_str().unwrap(),
+                route
+            );
+        }
+
+        let path = dir.join("plugins.toml");
+        fs::write(&path, file).unwrap();
+
+        let result = Plugins::load(path.to_str().unwrap());
+        fs::remove_dir_all(dir).unwrap();
+
+        result
+    }
+
+    fn decode(
+        plugins: &Plugins,
+        app_id: &str,
+        dev_id: &str,
+        port: u32,
+    ) -> Result<Option<Map<String, Value>>, String> {
+        let line = format!(
+            r#"{{
+                "end_device_ids": {{"device_id": "{:}", "application_ids": {{"application_id": "{:}"}}}},
+                "uplink_message": {{"f_port": {:}, "received_at": "2020-01-01T12:00:00Z", "frm_payload": "AQI="}}
+            }}"#,
+            dev_id, app_id, port
+        );
+        let (_, uplink) = Formats::default().parse(&line).unwrap();
+
+        plugins.decode(&uplink)
+    }
+
+    #[test]
+    fn decodes_payloads() {
+        let json = r#"{"voltage": {"value": 3.3, "unit": "V"}, "ok": true}"#;
+        let plugins = load("decode", "", &[("", constant(json))]).unwrap();
+
+        assert_eq!(
+            Value::Object(decode(&plugins, "app", "node", 1).unwrap().unwrap()),
+            serde_json::from_str::<Value>(json).unwrap()
+        );
+    }
+
+    #[test]
+    fn null_means_not_handled() {
+        let plugins = load("null", "", &[("", constant("null"))]).unwrap();
+
+        assert!(decode(&plugins, "app", "node", 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn refuses_results_out_of_bounds() {
+        let oob = module(
+            &returning(60000, 100000),
+            b"",
+            &["memory", "alloc", "decode"],
+        );
+        let plugins = load("oob", "", &[("", oob)]).unwrap();
+
+        assert_eq!(
+            decode(&plugins, "app", "node", 1).unwrap_err(),
+            "result is out of bounds"
+        );
+    }
+
+    #[test]
+    fn stops_endless_loops() {
+        // loop { br 0 }:
+        let endless = module(
+            &[&[0x03, 0x40, 0x0c, 0x00, 0x0b][..], &returning(0, 0)].concat(),
+            b"",
+            &["memory", "alloc", "decode"],
+        );
+        let plugins = load("loop", "fuel = 10000", &[("", endless)]).unwrap();
+
+        let err = decode(&plugins, "app", "node", 1).unwrap_err();
+        assert!(err.contains("fuel"), "{:}", err);
+    }
+
+    #[test]
+    fn limits_memory_growth() {
+        // Traps if the memory can't grow by another page:
+        // if (memory.grow(1) == -1) { unreachable }
+        let json = r#"{"grown": true}"#;
+        let growing = module(
+            &[
+                &[
+                    0x41, 0x01, 0x40, 0x00, 0x41, 0x7f, 0x46, 0x04, 0x40, 0x00, 0x0b,
+                ][..],
+                &returning(1024, json.len()),
+            ]
+            .concat(),
+            json.as_bytes(),
+            &["memory", "alloc", "decode"],
+        );
+
+        let plugins = load("grow", "", &[("", growing.clone())]).unwrap();
+        assert!(decode(&plugins, "app", "node", 1).unwrap().is_some());
+
+        let plugins = load("grow-limited", "max_memory = 65536", &[("", growing)]).unwrap();
+        let err = decode(&plugins, "app", "node", 1).unwrap_err();
+        assert!(err.contains("unreachable"), "{:}", err);
+    }
+
+    #[test]
+    fn refuses_modules_without_exports() {
+        let incomplete = module(&returning(0, 0), b"", &["memory", "decode"]);
+
+        match load("exports", "", &[("", incomplete)]) {
+            Err(err) => assert!(err.message().contains("\"alloc\" is not exported")),
+            Ok(_) => panic!("a plugin without \"alloc\" has been loaded"),
+        }
+    }
+
+    #[test]
+    fn routes_uplinks() {
+        let plugins = load(
+            "routes",
+            "",
+            &[
+                (
+                    "app_id = \"app\"\ndev_id = \"node-1\"\nport = 2",
+                    constant(r#"{"plugin": 1}"#),
+                ),
+                ("port = 3", constant(r#"{"plugin": 2}"#)),
+            ],
+        )
+        .unwrap();
+
+        for (app_id, dev_id, port, plugin) in [
+            ("app", "node-1", 2, Some(1)),
+            ("app", "node-2", 2, None),
+            ("other", "node-1", 2, None),
+            ("app", "node-1", 1, None),
+            ("app", "node-1", 3, Some(2)),
+            ("other", "node-2", 3, Some(2)),
+        ] {
+            let fields = decode(&plugins, app_id, dev_id, port).unwrap();
+            assert_eq!(
+                fields.map(|fields| fields["plugin"].clone()),
+                plugin.map(Value::from),
+                "{:} {:} {:}",
+                app_id,
+                dev_id,
+                port
+            );
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{info, Error};
+use chrono::{DateTime, SecondsFormat};
+use rusqlite::Connection;
+use std::time::Duration;
+
+// Parses an age like "90d", "12h", "30m" or "45s":
+pub fn parse_age(age: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid age \"{:}\" (e.g. 90d, 12h, 30m or 45s)", age);
+
+    let unit = match age.chars().last().ok_or_else(invalid)? {
+        'd' => 24 * 60 * 60,
+        'h' => 60 * 60,
+        'm' => 60,
+        's' => 1,
+        _ => return Err(invalid()),
+    };
+
+    let count: u64 = age[..age.len() - 1].parse().map_err(|_| invalid())?;
+
+    count
+        .checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+// Deletes the uplinks before the given time (in nanoseconds since the Unix epoch), along with their receptions and measurements.
+// Rejected messages are deleted as well if "rejected" is set (by the time they have been rejected).
+// Applications and devices are kept, so their first-seen times still tell when they have been seen first.
+// Uplinks whose time couldn't be parsed (i.e. without "time_ns") are never deleted.
+pub fn run(db_connection: &Connection, before_ns: i64, rejected: bool) -> Result<(), Error> {
+    let before = DateTime::from_timestamp_nanos(before_ns);
+
+    db_connection.execute_batch("BEGIN")?;
+
+    let result = (|| -> Result<(usize, usize, usize, usize), Error> {
+        let receptions = db_connection.execute(
+            "DELETE FROM gateways WHERE data_id IN (SELECT id FROM data WHERE time_ns < ?)",
+            [before_ns],
+        )?;
+
+        let measurements = db_connection.execute(
+            "DELETE FROM measurements WHERE data_id IN (SELECT id FROM data WHERE time_ns < ?)",
+            [before_ns],
+        )?;
+
+        let uplinks = db_connection.execute("DELETE FROM data WHERE time_ns < ?", [before_ns])?;
+
+        // The rejection times are written like this, so they can be compared as strings:
+        let rejected = if rejected {
+            db_connection.execute(
+                "DELETE FROM rejected WHERE time < ?",
+                [before.to_rfc3339_opts(SecondsFormat::Millis, true)],
+            )?
+        } else {
+            0
+        };
+
+        db_connection.execute_batch("COMMIT")?;
+
+        Ok((uplinks, receptions, measurements, rejected))
+    })();
+
+    let (uplinks, receptions, measurements, rejected) = match result {
+        Ok(counts) => counts,
+        Err(err) => {
+            _ = db_connection.execute_batch("ROLLBACK");
+            return Err(err);
+        }
+    };
+
+    info!(
+        "Pruned {:} uplinks ({:} receptions, {:} measurements) and {:} rejected messages before {:}",
+        uplinks,
+        receptions,
+        measurements,
+        rejected,
+        before.to_rfc3339_opts(SecondsFormat::Secs, true)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use crate::{info, Error, Ingestor, Outcome};
+use std::str;
+
+// Runs all messages from the "rejected" table through the ingestor again.
+// Those that succeed now are moved into "data" (unless they are duplicates), the others keep their row (with the current error).
+// Everything happens in a single transaction, so an interrupted run doesn't leave anything behind.
+pub fn run(ingestor: &mut Ingestor) -> Result<(), Error> {
+    let db_connection = ingestor.db_connection;
+    let rejected = db_connection
+        .prepare("SELECT id, line, raw_line FROM rejected ORDER BY id")?
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<Vec<u8>>>(2)?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut delete_rejected = db_connection.prepare("DELETE FROM rejected WHERE id = ?")?;
+    let mut update_rejected = db_connection
+        .prepare("UPDATE rejected SET error_kind = ?, error_message = ? WHERE id = ?")?;
+
+    let mut recovered = 0;
+    let mut duplicates = 0;
+    let mut failed = 0;
+
+    db_connection.execute_batch("BEGIN")?;
+
+    for (id, line, raw_line) in &rejected {
+        // A failing message must not leave half of its rows behind:
+        db_connection.execute_batch("SAVEPOINT message")?;
+
+        // Messages that aren't valid UTF-8 are only kept as lossy line, which must not be stored as if it was the original:
+        let result = match raw_line {
+            Some(raw_line) => str::from_utf8(raw_line)
+                .map_err(Error::from)
+                .and_then(|line| ingestor.ingest_line(line)),
+            None => ingestor.ingest_line(line),
+        };
+
+        match result {
+            Ok(outcome) => {
+                delete_rejected.execute([id])?;
+
+                match outcome {
+                    Outcome::Inserted => recovered += 1,
+                    Outcome::Duplicate => duplicates += 1,
+                }
+            }
+            Err(err) => {
+                println!("Rejected message {:} still fails:\n{:}", id, err);
+                db_connection.execute_batch("ROLLBACK TO message")?;
+                update_rejected.execute((err.kind(), err.message(), id))?;
+                failed += 1;
+            }
+        }
+
+        db_connection.execute_batch("RELEASE message")?;
+    }
+
+    db_connection.execute_batch("COMMIT")?;
+
+    info!(
+        "Reprocessed {:} rejected messages ({:} recovered, {:} duplicates, {:} still failing)",
+        rejected.len(),
+        recovered,
+        duplicates,
+        failed
+    );
+
+    Ok(())
+}
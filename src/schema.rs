@@ -0,0 +1,824 @@
+use crate::{env_var, info, parse_time, Error};
+use rusqlite::{Connection, OptionalExtension};
+
+// A single step in the evolution of our DB schema.
+// The schema version (stored as "user_version" in the DB header) is the number of applied migrations.
+struct Migration {
+    description: &'static str,
+    apply: fn(&Connection) -> Result<(), Error>,
+}
+
+// All migrations in the order they have to be applied.
+// Never change a migration that has been released, add a new one instead.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create the data table",
+        apply: create_data_table,
+    },
+    Migration {
+        description: "add IDs, optional locations, radio settings, gateways and rejected messages",
+        apply: add_receptions_and_rejections,
+    },
+    Migration {
+        description: "add the time as indexed epoch in nanoseconds",
+        apply: add_time_ns,
+    },
+    Migration {
+        description: "move applications and devices into tables of their own",
+        apply: normalize_devices,
+    },
+    Migration {
+        description: "add the measurements decoded from payloads",
+        apply: add_measurements,
+    },
+    Migration {
+        description: "add units to the measurements",
+        apply: add_measurement_units,
+    },
+    Migration {
+        description: "add the payload fields decoded by the network server",
+        apply: add_decoded,
+    },
+    Migration {
+        description: "add the MIC verification result of raw frames",
+        apply: add_mic_valid,
+    },
+    Migration {
+        description: "add the errors of payloads that couldn't be decoded",
+        apply: add_decode_error,
+    },
+    Migration {
+        description: "add the fields returned by decoder plugins and scripts",
+        apply: add_decoder_fields,
+    },
+    Migration {
+        description: "add the DevAddr of raw frames",
+        apply: add_dev_addr,
+    },
+    Migration {
+        description: "add the bytes of rejected messages that aren't valid UTF-8",
+        apply: add_raw_line,
+    },
+];
+
+// Brings the schema of the DB up to date.
+// DBs that have been written by a newer version of this program (i.e. with unknown migrations) are refused.
+pub fn migrate(db_connection: &Connection) -> Result<(), Error> {
+    let version: usize = db_connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    if version > MIGRATIONS.len() {
+        return Err(Error::Schema(format!(
+            "the DB has schema version {:}, but this program only knows up to version {:}",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let version = index + 1;
+
+        // Every migration is applied atomically together with its version number:
+        db_connection.execute_batch("BEGIN")?;
+
+        let result = (migration.apply)(db_connection).and_then(|()| {
+            db_connection.execute_batch(&format!("PRAGMA user_version = {:}", version))?;
+            db_connection.execute_batch("COMMIT")?;
+            Ok(())
+        });
+
+        if let Err(err) = result {
+            _ = db_connection.execute_batch("ROLLBACK");
+            return Err(err);
+        }
+
+        info!(
+            "Migrated DB schema to version {:} ({:})",
+            version, migration.description
+        );
+    }
+
+    Ok(())
+}
+
+// Refuses DBs whose schema isn't up to date (for commands that shouldn't migrate on their own):
+pub fn check(db_connection: &Connection) -> Result<(), Error> {
+    let version: usize = db_connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    if version != MIGRATIONS.len() {
+        return Err(Error::Schema(format!(
+            "the DB has schema version {:}, but this program expects version {:} (see the \"migrate\" command)",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+
+    Ok(())
+}
+
+// Returns the names of the columns of the given table:
+fn table_columns(db_connection: &Connection, table: &str) -> Result<Vec<String>, Error> {
+    // Unlike "table_info", "table_xinfo" includes generated columns:
+    let columns = db_connection
+        .prepare(&format!("PRAGMA table_xinfo({:})", table))?
+        .query_map([], |row| row.get(1))?
+        .collect::<Result<_, _>>()?;
+
+    Ok(columns)
+}
+
+// Version 1: The schema of the very first release.
+// DBs from before the introduction of migrations already have this table.
+fn create_data_table(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS data (
+        	app_id TEXT NOT NULL, dev_id TEXT NOT NULL, hardware_serial TEXT NOT NULL,
+        	port INTEGER NOT NULL, counter INTEGER NOT NULL, time TEXT NOT NULL,
+        	lon REAL NOT NULL, lat REAL NOT NULL, alt REAL NOT NULL, payload BLOB NOT NULL
+        )",
+    )?;
+
+    Ok(())
+}
+
+// Version 2: "data" gets an explicit ID (so that "gateways" can reference it), nullable locations and radio settings.
+// SQLite can't change columns in place, so the table is rebuilt.
+// Columns that are already there (i.e. in DBs without a version number) are copied, IDs are taken from the row IDs.
+fn add_receptions_and_rejections(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch(
+        "CREATE TABLE data_new (
+        	id INTEGER PRIMARY KEY, app_id TEXT NOT NULL, dev_id TEXT NOT NULL, hardware_serial TEXT NOT NULL,
+        	port INTEGER NOT NULL, counter INTEGER NOT NULL, time TEXT NOT NULL,
+        	lon REAL, lat REAL, alt REAL, location_source TEXT, payload BLOB NOT NULL,
+        	frequency INTEGER, modulation TEXT, data_rate TEXT, spreading_factor INTEGER,
+        	bandwidth INTEGER, coding_rate TEXT, airtime INTEGER
+        )",
+    )?;
+
+    let new_columns = table_columns(db_connection, "data_new")?;
+    let columns: Vec<String> = table_columns(db_connection, "data")?
+        .into_iter()
+        .filter(|column| column != "id" && new_columns.contains(column))
+        .collect();
+
+    db_connection.execute_batch(&format!(
+        "INSERT INTO data_new (id, {columns}) SELECT rowid, {columns} FROM data;
+        DROP TABLE data;
+        ALTER TABLE data_new RENAME TO data;
+        CREATE TABLE IF NOT EXISTS gateways (
+        	data_id INTEGER NOT NULL REFERENCES data(id), gtw_id TEXT NOT NULL,
+        	time TEXT, timestamp INTEGER, channel INTEGER, rf_chain INTEGER,
+        	rssi REAL, snr REAL, lon REAL, lat REAL, alt REAL
+        );
+        CREATE INDEX IF NOT EXISTS gateways_data_id ON gateways(data_id);
+        CREATE TABLE IF NOT EXISTS rejected (
+        	id INTEGER PRIMARY KEY, time TEXT NOT NULL,
+        	error_kind TEXT NOT NULL, error_message TEXT NOT NULL, line TEXT NOT NULL
+        );",
+        columns = columns.join(", ")
+    ))?;
+
+    Ok(())
+}
+
+// Version 3: The time as nanoseconds since the Unix epoch, so that time ranges can be queried by index.
+// Existing rows are parsed here. Those with an unparseable time are flagged by NULL.
+fn add_time_ns(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch("ALTER TABLE data ADD COLUMN time_ns INTEGER")?;
+
+    let rows = db_connection
+        .prepare("SELECT id, time FROM data")?
+        .query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut update = db_connection.prepare("UPDATE data SET time_ns = ? WHERE id = ?")?;
+    let mut unparseable = 0;
+
+    for (id, time) in rows {
+        match parse_time(&time) {
+            Ok(time_ns) => _ = update.execute((time_ns, id))?,
+            Err(_) => unparseable += 1,
+        }
+    }
+
+    if unparseable > 0 {
+        println!(
+            "{:} rows have an unparseable time, their time_ns is NULL",
+            unparseable
+        );
+    }
+
+    db_connection.execute_batch("CREATE INDEX data_time_ns ON data(time_ns)")?;
+
+    Ok(())
+}
+
+// Version 4: Every application and device is stored once (with first-seen / last-seen times and the latest location).
+// "data" references the device instead of repeating its IDs, which means the table is rebuilt.
+// The view "uplinks" joins everything back together, so it can be queried like "data" before.
+fn normalize_devices(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch(
+        "CREATE TABLE applications (
+        	id INTEGER PRIMARY KEY, app_id TEXT NOT NULL UNIQUE,
+        	first_seen_ns INTEGER, last_seen_ns INTEGER
+        );
+        CREATE TABLE devices (
+        	id INTEGER PRIMARY KEY, application_id INTEGER NOT NULL REFERENCES applications(id),
+        	dev_id TEXT NOT NULL, hardware_serial TEXT NOT NULL,
+        	first_seen_ns INTEGER, last_seen_ns INTEGER,
+        	lon REAL, lat REAL, alt REAL, location_source TEXT, location_time_ns INTEGER,
+        	UNIQUE (application_id, dev_id)
+        );
+        INSERT INTO applications (app_id, first_seen_ns, last_seen_ns)
+        	SELECT app_id, MIN(time_ns), MAX(time_ns) FROM data GROUP BY app_id;
+        INSERT INTO devices (application_id, dev_id, hardware_serial, first_seen_ns, last_seen_ns)
+        	SELECT applications.id, data.dev_id,
+        		(SELECT latest.hardware_serial FROM data AS latest
+        			WHERE latest.app_id = data.app_id AND latest.dev_id = data.dev_id
+        			ORDER BY latest.time_ns DESC LIMIT 1),
+        		MIN(data.time_ns), MAX(data.time_ns)
+        	FROM data JOIN applications ON applications.app_id = data.app_id
+        	GROUP BY applications.id, data.dev_id;
+        UPDATE devices SET (lon, lat, alt, location_source, location_time_ns) = (
+        	SELECT data.lon, data.lat, data.alt, data.location_source, data.time_ns
+        	FROM data JOIN applications ON applications.app_id = data.app_id
+        	WHERE applications.id = devices.application_id AND data.dev_id = devices.dev_id AND data.lon IS NOT NULL
+        	ORDER BY data.time_ns DESC LIMIT 1
+        );
+        CREATE TABLE data_new (
+        	id INTEGER PRIMARY KEY, device_id INTEGER NOT NULL REFERENCES devices(id),
+        	port INTEGER NOT NULL, counter INTEGER NOT NULL, time TEXT NOT NULL, time_ns INTEGER,
+        	lon REAL, lat REAL, alt REAL, location_source TEXT, payload BLOB NOT NULL,
+        	frequency INTEGER, modulation TEXT, data_rate TEXT, spreading_factor INTEGER,
+        	bandwidth INTEGER, coding_rate TEXT, airtime INTEGER
+        );
+        INSERT INTO data_new
+        	SELECT data.id, devices.id, data.port, data.counter, data.time, data.time_ns,
+        		data.lon, data.lat, data.alt, data.location_source, data.payload,
+        		data.frequency, data.modulation, data.data_rate, data.spreading_factor,
+        		data.bandwidth, data.coding_rate, data.airtime
+        	FROM data
+        	JOIN applications ON applications.app_id = data.app_id
+        	JOIN devices ON devices.application_id = applications.id AND devices.dev_id = data.dev_id;
+        DROP TABLE data;
+        ALTER TABLE data_new RENAME TO data;
+        CREATE INDEX data_time_ns ON data(time_ns);
+        CREATE INDEX data_device_id ON data(device_id);
+        CREATE VIEW uplinks AS
+        	SELECT data.*, applications.app_id, devices.dev_id, devices.hardware_serial
+        	FROM data
+        	JOIN devices ON devices.id = data.device_id
+        	JOIN applications ON applications.id = devices.application_id;",
+    )?;
+
+    Ok(())
+}
+
+// Version 5: The values decoded from the payload of an uplink (e.g. by Cayenne LPP).
+// Existing rows are not decoded here, the payloads are still there to do so later.
+fn add_measurements(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch(
+        "CREATE TABLE measurements (
+        	data_id INTEGER NOT NULL REFERENCES data(id), channel INTEGER,
+        	type TEXT NOT NULL, value REAL NOT NULL
+        );
+        CREATE INDEX measurements_data_id ON measurements(data_id);",
+    )?;
+
+    Ok(())
+}
+
+// Version 6: Measurements of custom payload layouts may have a unit.
+fn add_measurement_units(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch("ALTER TABLE measurements ADD COLUMN unit TEXT")?;
+
+    Ok(())
+}
+
+// Version 7: The fields decoded by the payload formatter of the network server, as JSON.
+fn add_decoded(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch("ALTER TABLE data ADD COLUMN decoded TEXT")?;
+
+    Ok(())
+}
+
+// Version 8: Whether the MIC of a raw frame is valid (NULL if it hasn't been verified).
+fn add_mic_valid(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch("ALTER TABLE data ADD COLUMN mic_valid INTEGER")?;
+
+    Ok(())
+}
+
+// Version 9: Why the payload of an uplink couldn't be decoded (NULL if it could or if there is no decoder for it).
+fn add_decode_error(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch("ALTER TABLE data ADD COLUMN decode_error TEXT")?;
+
+    Ok(())
+}
+
+// Version 10: Everything a decoder plugin or script has returned for an uplink, as JSON.
+// Unlike "decoded", this is computed by us (and not by the network server).
+fn add_decoder_fields(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch("ALTER TABLE data ADD COLUMN decoder_fields TEXT")?;
+
+    Ok(())
+}
+
+// Version 11: The DevAddr of raw frames (as 8 hex digits), which identifies the devices we have no session keys for.
+fn add_dev_addr(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch("ALTER TABLE data ADD COLUMN dev_addr TEXT")?;
+
+    Ok(())
+}
+
+// Version 12: The exact bytes of a rejected message that isn't valid UTF-8 (NULL for all others).
+// Its "line" only holds a lossy conversion, which must not be reprocessed.
+fn add_raw_line(db_connection: &Connection) -> Result<(), Error> {
+    db_connection.execute_batch("ALTER TABLE rejected ADD COLUMN raw_line BLOB")?;
+
+    Ok(())
+}
+
+// The columns of "data" that may be part of the dedup key.
+// "dev_id" is accepted as alias for "device_id".
+const DEDUP_COLUMNS: &[&str] = &["device_id", "port", "counter", "time", "time_ns", "payload"];
+
+// Uplinks are the same if they come from the same device with the same counter at the same time:
+const DEFAULT_DEDUP_KEY: &[&str] = &["device_id", "counter", "time"];
+
+// How "setup" prepares a DB: The dedup key (if there is one) and the fields of "decoded" that are flattened into columns.
+// Uplinks that are duplicates according to a new dedup key are only removed if that has been asked for.
+pub struct SchemaConfig {
+    dedup_key: Option<Vec<String>>,
+    decoded_columns: Vec<DecodedColumn>,
+    remove_duplicates: bool,
+}
+
+impl Default for SchemaConfig {
+    fn default() -> Self {
+        SchemaConfig {
+            dedup_key: Some(
+                DEFAULT_DEDUP_KEY
+                    .iter()
+                    .map(|&column| String::from(column))
+                    .collect(),
+            ),
+            decoded_columns: Vec::new(),
+            remove_duplicates: false,
+        }
+    }
+}
+
+impl SchemaConfig {
+    // Without a dedup key, uplinks are not deduplicated at all.
+    pub fn new(
+        dedup_key: Option<&[&str]>,
+        decoded_columns: Vec<DecodedColumn>,
+    ) -> Result<SchemaConfig, Error> {
+        let dedup_key = dedup_key
+            .map(|columns| {
+                columns
+                    .iter()
+                    .map(|column| match column.trim() {
+                        "dev_id" => Ok(String::from("device_id")),
+                        column if DEDUP_COLUMNS.contains(&column) => Ok(String::from(column)),
+                        column => Err(Error::Config(format!(
+                            "invalid dedup key column \"{:}\" (valid are {:})",
+                            column,
+                            DEDUP_COLUMNS.join(", ")
+                        ))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?;
+
+        Ok(SchemaConfig {
+            dedup_key,
+            decoded_columns,
+            remove_duplicates: false,
+        })
+    }
+
+    // Allows "setup" to delete uplinks that stand in the way of the dedup key:
+    pub fn removing_duplicates(self, remove_duplicates: bool) -> SchemaConfig {
+        SchemaConfig {
+            remove_duplicates,
+            ..self
+        }
+    }
+
+    // Reads the dedup key from "TTN2SQLITE_DEDUP_KEY" as comma-separated list of columns ("none" disables deduplication).
+    // Reads the flattened fields from "TTN2SQLITE_DECODED_COLUMNS" as comma-separated list of "path:type" (see "DecodedColumn").
+    pub fn from_env() -> Result<SchemaConfig, Error> {
+        let dedup_key = env_var("TTN2SQLITE_DEDUP_KEY");
+        let dedup_key: Option<Vec<&str>> = match dedup_key.as_deref() {
+            Some("none") => None,
+            Some(key) => Some(key.split(',').collect()),
+            None => Some(DEFAULT_DEDUP_KEY.to_vec()),
+        };
+
+        let decoded_columns = env_var("TTN2SQLITE_DECODED_COLUMNS")
+            .map(|columns| {
+                columns
+                    .split(',')
+                    .map(|column| {
+                        let (path, sql_type) =
+                            column.trim().split_once(':').unwrap_or((column, ""));
+                        DecodedColumn::new(path, sql_type)
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        SchemaConfig::new(dedup_key.as_deref(), decoded_columns)
+    }
+}
+
+// Enforces the dedup key by a unique index on "data".
+// The index is only recreated if the key has changed since the last run.
+// Uplinks that are already in the DB twice (e.g. from replayed captures or a key with fewer columns) prevent the index.
+// Unless "remove_duplicates" is set, they are only counted. Otherwise, the one that has been stored first is kept.
+fn create_dedup_index(
+    db_connection: &Connection,
+    key: Option<&[String]>,
+    remove_duplicates: bool,
+) -> Result<(), Error> {
+    let existing_sql: Option<String> = db_connection
+        .query_row(
+            "SELECT sql FROM sqlite_master WHERE type = 'index' AND name = 'data_dedup'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    let sql = key.map(|columns| {
+        format!(
+            "CREATE UNIQUE INDEX data_dedup ON data({:})",
+            columns.join(", ")
+        )
+    });
+
+    if existing_sql == sql {
+        return Ok(());
+    }
+
+    db_connection.execute_batch("BEGIN")?;
+
+    let result = (|| -> Result<usize, Error> {
+        db_connection.execute_batch("DROP INDEX IF EXISTS data_dedup")?;
+
+        let (columns, sql) = match (key, &sql) {
+            (Some(columns), Some(sql)) => (columns, sql),
+            _ => return Ok(0),
+        };
+
+        // Like the index, rows with NULL in a key column are never duplicates:
+        let duplicates = format!(
+            "SELECT id FROM data WHERE {:} AND id NOT IN (SELECT MIN(id) FROM data GROUP BY {:})",
+            columns
+                .iter()
+                .map(|column| format!("{:} IS NOT NULL", column))
+                .collect::<Vec<_>>()
+                .join(" AND "),
+            columns.join(", ")
+        );
+
+        let count: usize = db_connection.query_row(
+            &format!("SELECT COUNT(*) FROM ({:})", duplicates),
+            [],
+            |row| row.get(0),
+        )?;
+
+        if count > 0 && !remove_duplicates {
+            return Err(Error::Schema(format!(
+                "{:} uplinks are duplicates according to the dedup key {:} (keep the old key or remove them by \"migrate --remove-duplicates\")",
+                count,
+                columns.join(",")
+            )));
+        }
+
+        if count > 0 {
+            db_connection.execute(
+                &format!("DELETE FROM gateways WHERE data_id IN ({:})", duplicates),
+                [],
+            )?;
+            db_connection.execute(
+                &format!(
+                    "DELETE FROM measurements WHERE data_id IN ({:})",
+                    duplicates
+                ),
+                [],
+            )?;
+            db_connection.execute(
+                &format!("DELETE FROM data WHERE id IN ({:})", duplicates),
+                [],
+            )?;
+        }
+
+        db_connection.execute_batch(sql)?;
+
+        Ok(count)
+    })()
+    .and_then(|removed| {
+        db_connection.execute_batch("COMMIT")?;
+        Ok(removed)
+    });
+
+    match result {
+        Ok(0) => Ok(()),
+        Ok(removed) => {
+            println!(
+                "Removed {:} uplinks that are duplicates according to the dedup key",
+                removed
+            );
+            Ok(())
+        }
+        Err(err) => {
+            // The old index (if there was one) stays in place:
+            _ = db_connection.execute_batch("ROLLBACK");
+            Err(err)
+        }
+    }
+}
+
+// A field of "decoded" that is flattened into a typed column of "data":
+pub struct DecodedColumn {
+    path: Vec<String>,
+    sql_type: &'static str,
+}
+
+impl DecodedColumn {
+    const SQL_TYPES: &'static [&'static str] = &["INTEGER", "REAL", "TEXT"];
+
+    // A path consists of field names separated by ".", the type is one of "INTEGER", "REAL" or "TEXT".
+    pub fn new(path: &str, sql_type: &str) -> Result<DecodedColumn, Error> {
+        let invalid = || {
+            Error::Config(format!(
+                "invalid decoded column \"{:}:{:}\" (expected \"path:type\" with type {:})",
+                path,
+                sql_type,
+                DecodedColumn::SQL_TYPES.join(", ")
+            ))
+        };
+
+        let sql_type = DecodedColumn::SQL_TYPES
+            .iter()
+            .find(|known| known.eq_ignore_ascii_case(sql_type))
+            .ok_or_else(invalid)?;
+
+        // The names end up in SQL, so they are restricted to what needs no quoting:
+        let path: Vec<String> = path.split('.').map(String::from).collect();
+
+        if path.iter().any(|name| {
+            name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }) {
+            return Err(invalid());
+        }
+
+        Ok(DecodedColumn { path, sql_type })
+    }
+
+    // E.g. "status.battery" is stored in "decoded_status_battery":
+    fn name(&self) -> String {
+        format!("decoded_{:}", self.path.join("_"))
+    }
+}
+
+// Adds the flattened fields to "data" as virtual columns, so they are extracted from "decoded" when they are read.
+// That way, they are available for existing rows as well (and don't take up space).
+// Columns that are not configured anymore are kept, as are the ones that are already there (even with another type).
+fn add_decoded_columns(db_connection: &Connection, columns: &[DecodedColumn]) -> Result<(), Error> {
+    let existing = table_columns(db_connection, "data")?;
+
+    for column in columns {
+        let name = column.name();
+
+        if existing.contains(&name) {
+            continue;
+        }
+
+        db_connection.execute_batch(&format!(
+            "ALTER TABLE data ADD COLUMN {:} {:} AS (json_extract(decoded, '$.{:}'))",
+            name,
+            column.sql_type,
+            column.path.join(".")
+        ))?;
+
+        info!(
+            "Added column {:} for decoded field {:}",
+            name,
+            column.path.join(".")
+        );
+    }
+
+    Ok(())
+}
+
+// Everything a DB needs before uplinks can be stored in it:
+// The migrations, the dedup key and the decoded columns.
+pub fn setup(db_connection: &Connection, config: &SchemaConfig) -> Result<(), Error> {
+    migrate(db_connection)?;
+    create_dedup_index(
+        db_connection,
+        config.dedup_key.as_deref(),
+        config.remove_duplicates,
+    )?;
+    add_decoded_columns(db_connection, &config.decoded_columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(db_connection: &Connection, table: &str) -> i64 {
+        db_connection
+            .query_row(&format!("SELECT COUNT(*) FROM {:}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_baseline_db() {
+        let db_connection = Connection::open_in_memory().unwrap();
+
+        // A DB as written by the very first release (without a schema version):
+        db_connection
+            .execute_batch(
+                "CREATE TABLE data (
+                	app_id TEXT NOT NULL, dev_id TEXT NOT NULL, hardware_serial TEXT NOT NULL,
+                	port INTEGER NOT NULL, counter INTEGER NOT NULL, time TEXT NOT NULL,
+                	lon REAL NOT NULL, lat REAL NOT NULL, alt REAL NOT NULL, payload BLOB NOT NULL
+                );
+                INSERT INTO data VALUES
+                	('app', 'node-1', '0004A30B001C0530', 1, 1, '2020-01-01T12:00:00Z', 10.0, 52.0, 50.0, x'0102'),
+                	('app', 'node-1', '0004A30B001C0531', 1, 2, '2020-01-01T13:00:00Z', 11.0, 53.0, 60.0, x'0304'),
+                	('other', 'node-2', '0004A30B001C0532', 2, 1, 'yesterday', 0.0, 0.0, 0.0, x'');",
+            )
+            .unwrap();
+
+        migrate(&db_connection).unwrap();
+
+        let version: usize = db_connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        // Every row keeps its ID (taken from the row ID) and is attributed to its device:
+        let uplinks: Vec<(i64, String, String, Option<i64>)> = db_connection
+            .prepare("SELECT id, app_id, dev_id, time_ns FROM uplinks ORDER BY id")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(
+            uplinks,
+            [
+                (
+                    1,
+                    String::from("app"),
+                    String::from("node-1"),
+                    Some(1_577_880_000_000_000_000)
+                ),
+                (
+                    2,
+                    String::from("app"),
+                    String::from("node-1"),
+                    Some(1_577_883_600_000_000_000)
+                ),
+                // Its time can't be parsed:
+                (3, String::from("other"), String::from("node-2"), None),
+            ]
+        );
+
+        let payload: Vec<u8> = db_connection
+            .query_row("SELECT payload FROM data WHERE id = 2", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(payload, [3, 4]);
+
+        // Devices get the latest hardware serial and location:
+        let device: (String, f64, f64, Option<f64>, i64, i64) = db_connection
+            .query_row(
+                "SELECT hardware_serial, lon, lat, alt, first_seen_ns, last_seen_ns FROM devices WHERE dev_id = 'node-1'",
+                [],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                    ))
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            device,
+            (
+                String::from("0004A30B001C0531"),
+                11.0,
+                53.0,
+                Some(60.0),
+                1_577_880_000_000_000_000,
+                1_577_883_600_000_000_000
+            )
+        );
+
+        assert_eq!(count(&db_connection, "applications"), 2);
+        assert_eq!(count(&db_connection, "devices"), 2);
+
+        // Migrating again is a no-op:
+        migrate(&db_connection).unwrap();
+        assert_eq!(count(&db_connection, "data"), 3);
+    }
+
+    #[test]
+    fn dedup_index_removes_existing_duplicates() {
+        let db_connection = Connection::open_in_memory().unwrap();
+        migrate(&db_connection).unwrap();
+
+        db_connection
+            .execute_batch(
+                "INSERT INTO applications (id, app_id) VALUES (1, 'app');
+                INSERT INTO devices (id, application_id, dev_id, hardware_serial) VALUES (1, 1, 'node', '');
+                INSERT INTO data (id, device_id, port, counter, time, payload) VALUES
+                	(1, 1, 1, 7, '2020-01-01T12:00:00Z', x''),
+                	(2, 1, 1, 7, '2020-01-01T12:00:00Z', x''),
+                	(3, 1, 1, 8, '2020-01-01T12:00:00Z', x'');
+                INSERT INTO gateways (data_id, gtw_id) VALUES (1, 'gw-1'), (2, 'gw-1'), (3, 'gw-1');
+                INSERT INTO measurements (data_id, type, value) VALUES (2, 'temperature', 20.5);",
+            )
+            .unwrap();
+
+        let key = [
+            String::from("device_id"),
+            String::from("counter"),
+            String::from("time"),
+        ];
+        // Nothing is removed without being asked for:
+        assert!(create_dedup_index(&db_connection, Some(&key), false).is_err());
+        assert_eq!(count(&db_connection, "data"), 3);
+
+        create_dedup_index(&db_connection, Some(&key), true).unwrap();
+
+        let ids: Vec<i64> = db_connection
+            .prepare("SELECT id FROM data ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(ids, [1, 3]);
+        assert_eq!(count(&db_connection, "gateways"), 2);
+        assert_eq!(count(&db_connection, "measurements"), 0);
+
+        // The index is in place now:
+        assert!(db_connection
+            .execute(
+                "INSERT INTO data (device_id, port, counter, time, payload) VALUES (1, 1, 8, '2020-01-01T12:00:00Z', x'')",
+                [],
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn dedup_key_change_keeps_distinct_uplinks() {
+        let db_connection = Connection::open_in_memory().unwrap();
+        setup(&db_connection, &SchemaConfig::default()).unwrap();
+
+        // Two different uplinks on the same port:
+        db_connection
+            .execute_batch(
+                "INSERT INTO applications (id, app_id) VALUES (1, 'app');
+                INSERT INTO devices (id, application_id, dev_id, hardware_serial) VALUES (1, 1, 'node', '');
+                INSERT INTO data (id, device_id, port, counter, time, payload) VALUES
+                	(1, 1, 1, 7, '2020-01-01T12:00:00Z', x'01'),
+                	(2, 1, 1, 8, '2020-01-01T12:01:00Z', x'02');",
+            )
+            .unwrap();
+
+        let config = SchemaConfig::new(Some(&["port"]), Vec::new()).unwrap();
+        let err = setup(&db_connection, &config).unwrap_err();
+        assert!(err.message().starts_with("1 uplinks are duplicates"));
+
+        // Both uplinks are still there and the old key is still enforced:
+        assert_eq!(count(&db_connection, "data"), 2);
+        assert!(db_connection
+            .execute(
+                "INSERT INTO data (device_id, port, counter, time, payload) VALUES (1, 1, 8, '2020-01-01T12:01:00Z', x'')",
+                [],
+            )
+            .is_err());
+    }
+}
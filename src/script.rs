@@ -0,0 +1,254 @@
+use crate::Error;
+use rhai::{module_resolvers::DummyModuleResolver, Blob, Dynamic, Engine, Map, Scope, AST, INT};
+use serde_json::{Map as JSONMap, Number, Value as JSONValue};
+use std::fs;
+
+// The limits of a script run.
+// A script that exceeds them fails with an error (instead of hanging or exhausting our memory).
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_ARRAY_SIZE: usize = 16 * 1024;
+const MAX_MAP_SIZE: usize = 1024;
+
+// A user-provided Rhai script that decodes payloads.
+// It must define "fn decode(bytes, port)" which gets the payload as blob and returns a map of fields, e.g.:
+//
+// fn decode(bytes, port) {
+//     if port != 3 { return (); }
+//     #{ temperature: #{ value: (bytes[0] << 8 | bytes[1]) / 100.0, unit: "°C" }, battery_low: bytes[2] > 0 }
+// }
+//
+// Returning "()" means that the script doesn't handle the payload.
+// The whole map is stored as JSON. Numbers, booleans and maps with a numeric "value" (and a "unit") are stored as measurements as well.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+}
+
+impl Script {
+    pub fn load(path: &str) -> Result<Script, Error> {
+        let source = fs::read_to_string(path)?;
+
+        let mut engine = Engine::new();
+        engine
+            .set_module_resolver(DummyModuleResolver::new())
+            .set_max_operations(MAX_OPERATIONS)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_ARRAY_SIZE)
+            .set_max_map_size(MAX_MAP_SIZE);
+
+        let ast = engine
+            .compile(&source)
+            .map_err(|err| Error::Config(format!("invalid script \"{:}\": {:}", path, err)))?;
+
+        if !ast
+            .iter_functions()
+            .any(|func| func.name == "decode" && func.params.len() == 2)
+        {
+            return Err(Error::Config(format!(
+                "script \"{:}\" doesn't define \"fn decode(bytes, port)\"",
+                path
+            )));
+        }
+
+        Ok(Script { engine, ast })
+    }
+
+    // Runs the script on a payload and converts its result to JSON.
+    // Nothing is returned if the script doesn't handle it.
+    pub fn decode(
+        &self,
+        payload: &[u8],
+        port: u32,
+    ) -> Result<Option<JSONMap<String, JSONValue>>, String> {
+        let result: Dynamic = self
+            .engine
+            .call_fn(
+                &mut Scope::new(),
+                &self.ast,
+                "decode",
+                (Blob::from(payload), INT::from(port)),
+            )
+            .map_err(|err| err.to_string())?;
+
+        if result.is_unit() {
+            return Ok(None);
+        }
+
+        match to_json(result) {
+            JSONValue::Object(fields) => Ok(Some(fields)),
+            _ => Err(String::from("decode must return a map or ()")),
+        }
+    }
+}
+
+// Converts what a script returns to JSON.
+// Blobs become arrays of bytes, everything JSON doesn't know (e.g. timestamps or functions) is stored as string.
+fn to_json(value: Dynamic) -> JSONValue {
+    if value.is_unit() {
+        JSONValue::Null
+    } else if let Ok(value) = value.as_bool() {
+        JSONValue::Bool(value)
+    } else if let Ok(value) = value.as_int() {
+        JSONValue::from(value)
+    } else if let Ok(value) = value.as_float() {
+        Number::from_f64(value).map_or(JSONValue::Null, JSONValue::Number)
+    } else if value.is_string() {
+        JSONValue::String(value.into_string().unwrap_or_default())
+    } else if value.is_blob() {
+        JSONValue::from(value.into_blob().unwrap_or_default())
+    } else if value.is_array() {
+        JSONValue::Array(
+            value
+                .into_array()
+                .unwrap_or_default()
+                .into_iter()
+                .map(to_json)
+                .collect(),
+        )
+    } else if value.is_map() {
+        JSONValue::Object(
+            value
+                .cast::<Map>()
+                .into_iter()
+                .map(|(name, field)| (name.to_string(), to_json(field)))
+                .collect(),
+        )
+    } else {
+        JSONValue::String(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{Decoders, DecodersConfig};
+    use crate::formats::Formats;
+    use std::env;
+
+    // Writes the source to a file of its own (tests run in parallel) and loads it through the given function:
+    fn load_with<T>(name: &str, source: &str, load: impl FnOnce(&str) -> T) -> T {
+        let path = env::temp_dir().join(format!(
+            "ttn2sqlite-script-{:}-{:}.rhai",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, source).unwrap();
+
+        let result = load(path.to_str().unwrap());
+        fs::remove_file(path).unwrap();
+
+        result
+    }
+
+    fn script(name: &str, source: &str) -> Script {
+        load_with(name, source, Script::load).unwrap()
+    }
+
+    #[test]
+    fn stops_endless_loops() {
+        let script = script("loop", "fn decode(bytes, port) { loop {} }");
+
+        let err = script.decode(&[], 1).unwrap_err();
+        assert!(err.contains("Too many operations"), "{:}", err);
+    }
+
+    #[test]
+    fn unit_means_not_handled() {
+        let script = script(
+            "unit",
+            "fn decode(bytes, port) { if port != 3 { return (); } #{ first: bytes[0] } }",
+        );
+
+        assert!(script.decode(&[7], 1).unwrap().is_none());
+        assert_eq!(
+            JSONValue::Object(script.decode(&[7], 3).unwrap().unwrap()),
+            serde_json::json!({ "first": 7 })
+        );
+    }
+
+    #[test]
+    fn refuses_results_other_than_maps() {
+        let script = script("array", "fn decode(bytes, port) { [1, 2] }");
+
+        assert!(script.decode(&[], 1).is_err());
+    }
+
+    #[test]
+    fn refuses_scripts_without_decode() {
+        for (name, source) in [
+            ("missing", "fn parse(bytes, port) { () }"),
+            ("params", "fn decode(bytes) { () }"),
+            ("syntax", "fn decode(bytes, port) {"),
+        ] {
+            assert!(load_with(name, source, Script::load).is_err());
+        }
+    }
+
+    #[test]
+    fn converts_maps_into_fields_and_measurements() {
+        let decoders = load_with(
+            "fields",
+            r#"fn decode(bytes, port) {
+                #{
+                    temperature: #{ value: (bytes[0] << 8 | bytes[1]) / 100.0, unit: "°C" },
+                    battery_low: bytes[2] > 0,
+                    raw: bytes,
+                    status: "ok"
+                }
+            }"#,
+            |path| {
+                Decoders::load(DecodersConfig {
+                    script_file: Some(String::from(path)),
+                    ..DecodersConfig::default()
+                })
+            },
+        )
+        .unwrap();
+
+        // The payload is 09 C4 01:
+        let (_, uplink) = Formats::default()
+            .parse(
+                r#"{
+                    "end_device_ids": {"device_id": "node", "application_ids": {"application_id": "app"}},
+                    "uplink_message": {"f_port": 1, "received_at": "2020-01-01T12:00:00Z", "frm_payload": "CcQB"}
+                }"#,
+            )
+            .unwrap();
+        let decoded = decoders.decode(&uplink).unwrap();
+
+        // Only numbers, booleans and maps with a value are measurements:
+        let measurements: Vec<_> = decoded
+            .measurements
+            .iter()
+            .map(|measurement| {
+                (
+                    measurement.kind.as_str(),
+                    measurement.value,
+                    measurement.unit.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            measurements,
+            [
+                ("battery_low", 1.0, None),
+                ("temperature", 25.0, Some("°C"))
+            ]
+        );
+
+        assert_eq!(
+            decoded.fields.unwrap(),
+            serde_json::json!({
+                "temperature": { "value": 25.0, "unit": "°C" },
+                "battery_low": true,
+                "raw": [9, 196, 1],
+                "status": "ok"
+            })
+        );
+    }
+}
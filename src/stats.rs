@@ -0,0 +1,86 @@
+use crate::Error;
+use chrono::{DateTime, SecondsFormat};
+use rusqlite::Connection;
+
+fn format_nanos(nanos: Option<i64>) -> String {
+    nanos.map_or(String::from("-"), |nanos| {
+        DateTime::from_timestamp_nanos(nanos).to_rfc3339_opts(SecondsFormat::Secs, true)
+    })
+}
+
+// Prints an overview of what is stored in the DB.
+// Unlike everything else, this is printed even if we are asked to be quiet (because it is the result).
+pub fn run(db_connection: &Connection) -> Result<(), Error> {
+    let count = |table: &str| -> Result<i64, Error> {
+        let count =
+            db_connection.query_row(&format!("SELECT COUNT(*) FROM {:}", table), [], |row| {
+                row.get(0)
+            })?;
+
+        Ok(count)
+    };
+
+    let (first_ns, last_ns): (Option<i64>, Option<i64>) =
+        db_connection.query_row("SELECT MIN(time_ns), MAX(time_ns) FROM data", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+
+    println!("Applications: {:}", count("applications")?);
+
+    // Every application on its own:
+    let mut stmt = db_connection.prepare(
+        "SELECT applications.app_id, COUNT(DISTINCT devices.id), COUNT(data.id), applications.last_seen_ns
+        	FROM applications
+        	LEFT JOIN devices ON devices.application_id = applications.id
+        	LEFT JOIN data ON data.device_id = devices.id
+        	GROUP BY applications.id ORDER BY applications.app_id",
+    )?;
+
+    let mut rows = stmt.query([])?;
+
+    while let Some(row) = rows.next()? {
+        // Raw frames without session keys belong to no application:
+        let app_id = row.get::<_, String>(0)?;
+
+        println!(
+            "  {:}: {:} devices, {:} uplinks, last seen {:}",
+            if app_id.is_empty() {
+                "(no application)"
+            } else {
+                &app_id
+            },
+            row.get::<_, i64>(1)?,
+            row.get::<_, i64>(2)?,
+            format_nanos(row.get(3)?)
+        );
+    }
+
+    println!("Devices:      {:}", count("devices")?);
+    println!(
+        "Uplinks:      {:} (from {:} to {:})",
+        count("data")?,
+        format_nanos(first_ns),
+        format_nanos(last_ns)
+    );
+    println!("Receptions:   {:}", count("gateways")?);
+    println!("Measurements: {:}", count("measurements")?);
+    println!("Rejected:     {:}", count("rejected")?);
+
+    // Why messages have been rejected:
+    let mut stmt = db_connection.prepare(
+        "SELECT error_kind, COUNT(*) FROM rejected GROUP BY error_kind ORDER BY COUNT(*) DESC",
+    )?;
+
+    let mut rows = stmt.query([])?;
+
+    while let Some(row) = rows.next()? {
+        println!(
+            "  {:} errors: {:}",
+            row.get::<_, Option<String>>(0)?
+                .unwrap_or_else(|| String::from("Unknown")),
+            row.get::<_, i64>(1)?
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use crate::{env_var, info, Delivery, Error, Input};
+use std::io::{Error as IOError, Read};
+use std::sync::mpsc::SyncSender;
+use tiny_http::{Method, Request, Response, Server};
+
+// The configuration of the HTTP webhook input mode.
+// It is read from the environment and only present if "TTN2SQLITE_WEBHOOK_ADDR" is set.
+pub struct WebhookConfig {
+    addr: String,
+    secret: Option<String>,
+    secret_header: String,
+}
+
+impl WebhookConfig {
+    // The header that carries the shared secret if none is configured explicitly.
+    // In The Things Stack, it is added as "additional header" to the webhook.
+    const DEFAULT_SECRET_HEADER: &'static str = "X-Webhook-Secret";
+
+    pub fn from_env() -> Result<Option<WebhookConfig>, Error> {
+        let addr = match env_var("TTN2SQLITE_WEBHOOK_ADDR") {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+
+        Ok(Some(WebhookConfig {
+            addr,
+            secret: env_var("TTN2SQLITE_WEBHOOK_SECRET"),
+            secret_header: env_var("TTN2SQLITE_WEBHOOK_SECRET_HEADER")
+                .unwrap_or_else(|| String::from(Self::DEFAULT_SECRET_HEADER)),
+        }))
+    }
+}
+
+// Requests with a larger body are rejected without reading them:
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// Compares two byte strings in constant time (with respect to their content), so the secret can't be guessed byte by byte.
+fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Runs an HTTP server and sends the body of every accepted POST request as input line.
+// The response is deferred until we know whether the uplink has been stored.
+pub fn serve(config: &WebhookConfig, sender: &SyncSender<Input>) -> Result<(), Error> {
+    let server =
+        Server::http(config.addr.as_str()).map_err(|err| IOError::other(err.to_string()))?;
+
+    info!("Listening for webhooks on {:}", config.addr);
+
+    for mut request in server.incoming_requests() {
+        let body = match read_body(config, &mut request) {
+            Ok(body) => body,
+            Err(status) => {
+                respond(request, status);
+                continue;
+            }
+        };
+
+        let completion = Box::new(move |delivery: Delivery| {
+            respond(
+                request,
+                match delivery {
+                    Delivery::Stored => 204,
+                    // Rejected messages are kept in "rejected" (to be reprocessed), so there is no point in sending them again.
+                    // Like MQTT acknowledging them, the status must not make the sender retry:
+                    Delivery::Rejected(Error::Json(_)) => 400,
+                    Delivery::Rejected(_) => 422,
+                    // Nothing has been stored, so sending it again may succeed (e.g. after the DB has been fixed):
+                    Delivery::Failed(_) => 500,
+                },
+            )
+        });
+
+        // The receiver is gone, so we are shutting down:
+        if sender
+            .send(Input::from_bytes(body, Some(completion)))
+            .is_err()
+        {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+fn respond(request: Request, status: u16) {
+    if let Err(err) = request.respond(Response::empty(status)) {
+        println!("Error while responding to webhook: {:}", err);
+    }
+}
+
+// Validates a request and reads its body.
+// If the request is rejected, the HTTP status code to respond with is returned.
+fn read_body(config: &WebhookConfig, request: &mut Request) -> Result<Vec<u8>, u16> {
+    if *request.method() != Method::Post {
+        return Err(405);
+    }
+
+    if let Some(secret) = &config.secret {
+        let authorized = request
+            .headers()
+            .iter()
+            .find(|header| {
+                header
+                    .field
+                    .as_str()
+                    .as_str()
+                    .eq_ignore_ascii_case(&config.secret_header)
+            })
+            .is_some_and(|header| secure_eq(header.value.as_bytes(), secret.as_bytes()));
+
+        if !authorized {
+            return Err(401);
+        }
+    }
+
+    if request.body_length().is_some_and(|len| len > MAX_BODY_SIZE) {
+        return Err(413);
+    }
+
+    // A body that isn't valid UTF-8 is still kept in "rejected":
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_SIZE as u64)
+        .read_to_end(&mut body)
+        .map_err(|_| 400_u16)?;
+
+    Ok(body)
+}